@@ -1,37 +1,263 @@
-use anyhow::Result;
+use crate::error::Error;
+use anyhow::{bail, Context, Result};
 use serde::{Deserialize, Serialize};
-use std::path::PathBuf;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
 
-#[derive(Debug, Serialize, Deserialize)]
+/// A named package repository
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Repo {
+    pub name: String,
+    pub url: String,
+    /// Where this repo's packages are installed instead of `root`, for
+    /// layering several repos into separate prefixes
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub root: Option<PathBuf>,
+}
+
+/// A named environment selected with `--profile`: its own paths, and
+/// optionally a config file of its own layered over the main one
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Profile {
+    pub config: Option<PathBuf>,
+    pub root: Option<PathBuf>,
+    pub db_dir: Option<PathBuf>,
+    pub cache_dir: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
 pub struct Config {
     pub root: PathBuf,
     pub db_dir: PathBuf,
     pub cache_dir: PathBuf,
     pub ports_dir: PathBuf,
+    /// Git URL `ports-sync` clones the ports tree from (empty = none)
+    pub ports_git: String,
+    /// Base URLs for `mirror://<alias>/` Portfile sources, on top of the
+    /// built-in aliases
+    pub mirrors: BTreeMap<String, String>,
     pub repo_url: String,
+    /// Repositories in priority order; when empty, `repo_url` is the only
+    /// repo, named "main"
+    pub repos: Vec<Repo>,
     pub arch: String,
+    /// Warn when the synced database is older than this many days (0 = never)
+    pub stale_days: u64,
+    /// Worker threads for parallel work such as `verify` (0 = one per CPU),
+    /// and concurrent package downloads (0 = 4)
+    pub jobs: usize,
+    /// Extract packages that don't depend on each other concurrently, using
+    /// `jobs` threads
+    pub parallel_extract: bool,
+    /// HTTP backend: "ureq" (built in) or "curl"
+    pub downloader: String,
+    /// Maximum download rate in bytes per second, e.g. "500k" (empty = unlimited)
+    pub download_limit: String,
+    /// Executables run, in name order, after each transaction
+    pub hooks_dir: PathBuf,
+    /// zstd level for packages made by `build` (1-22)
+    pub zstd_level: i32,
+    /// Hardlink identical installed files through a store under `cache_dir`
+    pub dedup: bool,
+    /// Tarballs of replaced versions kept per package under
+    /// `<cache_dir>/rollback` for downgrades (0 = keep none)
+    pub rollback_keep: usize,
+    /// Flush installed files and their directories to disk before recording
+    /// them, so an install survives a sudden power loss
+    pub fsync: bool,
+    /// Remember file hashes between `verify` runs and skip re-hashing files
+    /// whose size, mtime, ctime and inode are unchanged
+    pub verify_cache: bool,
+    /// minisign public key that package signatures must be made with (unset =
+    /// any key in the keyring)
+    pub signing_key: Option<PathBuf>,
+    /// Named environments, e.g. a build chroot or a test root
+    pub profiles: BTreeMap<String, Profile>,
 }
 
 impl Config {
-    pub fn load() -> Result<Self> {
-        let config_path = PathBuf::from("/etc/scarab/scarab.conf");
+    /// Load the config from `path`, or from the default location when no path
+    /// is given. An explicit path must exist.
+    ///
+    /// In user mode (`user`, or a non-root user who has a per-user config)
+    /// the defaults and config location follow the XDG base directories
+    /// instead of `/etc`, `/var/lib` and `/var/cache`.
+    pub fn load(path: Option<&Path>, user: bool) -> Result<Self> {
+        let user_config = user_config_path();
+        let user = user || (!is_root() && user_config.as_ref().is_some_and(|p| p.exists()));
+
+        let (defaults, config_path) = if user {
+            let config_path = user_config.context("Cannot determine the user config directory")?;
+            (Self::user_default()?, config_path)
+        } else {
+            (Self::default(), PathBuf::from("/etc/scarab/scarab.conf"))
+        };
+
+        if let Some(path) = path {
+            return Self::load_file(path, defaults);
+        }
 
         if config_path.exists() {
-            let content = std::fs::read_to_string(&config_path)?;
-            Ok(serde_json::from_str(&content)?)
+            Self::load_file(&config_path, defaults)
+        } else {
+            Ok(defaults)
+        }
+    }
+
+    /// Parse a config file, with any fields it leaves out taken from `defaults`
+    fn load_file(path: &Path, defaults: Self) -> Result<Self> {
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read config {}", path.display()))?;
+        let overrides: serde_json::Value = serde_json::from_str(&content)
+            .with_context(|| Error::Config(format!("Invalid config {}", path.display())))?;
+
+        let mut merged = serde_json::to_value(defaults)?;
+        match (merged.as_object_mut(), overrides.as_object()) {
+            (Some(base), Some(overrides)) => {
+                for (key, value) in overrides {
+                    base.insert(key.clone(), value.clone());
+                }
+            }
+            _ => bail!(Error::Config(format!(
+                "Invalid config {}: expected a JSON object",
+                path.display()
+            ))),
+        }
+
+        serde_json::from_value(merged)
+            .with_context(|| Error::Config(format!("Invalid config {}", path.display())))
+    }
+
+    /// Switch to the profile `name`: load its config file over this one,
+    /// then take whichever paths it sets
+    pub fn with_profile(self, name: &str) -> Result<Self> {
+        let Some(profile) = self.profiles.get(name).cloned() else {
+            let known: Vec<_> = self.profiles.keys().map(String::as_str).collect();
+            bail!(Error::NotFound(format!(
+                "No profile '{}' in the config (profiles: {})",
+                name,
+                if known.is_empty() { "none".to_string() } else { known.join(", ") }
+            )));
+        };
+        let mut cfg = match &profile.config {
+            Some(path) => Self::load_file(path, self)?,
+            None => self,
+        };
+        if let Some(root) = profile.root {
+            cfg.root = root;
+        }
+        if let Some(db_dir) = profile.db_dir {
+            cfg.db_dir = db_dir;
+        }
+        if let Some(cache_dir) = profile.cache_dir {
+            cfg.cache_dir = cache_dir;
+        }
+        Ok(cfg)
+    }
+
+    /// Per-user defaults under `$XDG_DATA_HOME/scarab` and `$XDG_CACHE_HOME/scarab`
+    fn user_default() -> Result<Self> {
+        let data_dir = dirs::data_dir()
+            .context("Cannot determine the user data directory")?
+            .join("scarab");
+        let cache_dir = dirs::cache_dir()
+            .context("Cannot determine the user cache directory")?
+            .join("scarab");
+
+        Ok(Self {
+            root: data_dir.join("root"),
+            db_dir: data_dir.join("db"),
+            cache_dir,
+            ports_dir: data_dir.join("ports"),
+            hooks_dir: dirs::config_dir()
+                .context("Cannot determine the user config directory")?
+                .join("scarab")
+                .join("hooks.d"),
+            ..Self::default()
+        })
+    }
+}
+
+impl Config {
+    /// Configured repositories, highest priority first
+    pub fn repos(&self) -> Vec<Repo> {
+        if self.repos.is_empty() {
+            vec![Repo {
+                name: "main".to_string(),
+                url: self.repo_url.clone(),
+                root: None,
+            }]
         } else {
-            Ok(Self::default())
+            self.repos.clone()
+        }
+    }
+
+    /// Root that packages from repo `repo` are installed into
+    pub fn root_for(&self, repo: &str) -> &Path {
+        self.repos
+            .iter()
+            .find(|r| r.name == repo)
+            .and_then(|r| r.root.as_deref())
+            .unwrap_or(&self.root)
+    }
+
+    /// `root` followed by every other root a repo installs into
+    pub fn roots(&self) -> Vec<&Path> {
+        let mut roots = vec![self.root.as_path()];
+        for root in self.repos.iter().filter_map(|r| r.root.as_deref()) {
+            if !roots.contains(&root) {
+                roots.push(root);
+            }
+        }
+        roots
+    }
+
+    /// Effective worker thread count
+    pub fn jobs(&self) -> usize {
+        match self.jobs {
+            0 => std::thread::available_parallelism().map_or(1, |n| n.get()),
+            n => n,
         }
     }
+}
+
+/// `$XDG_CONFIG_HOME/scarab/config`
+fn user_config_path() -> Option<PathBuf> {
+    dirs::config_dir().map(|d| d.join("scarab").join("config"))
+}
+
+fn is_root() -> bool {
+    // SAFETY: geteuid has no preconditions and cannot fail
+    unsafe { libc::geteuid() == 0 }
+}
 
+impl Default for Config {
     fn default() -> Self {
         Self {
             root: PathBuf::from("/"),
             db_dir: PathBuf::from("/var/lib/scarab"),
             cache_dir: PathBuf::from("/var/cache/scarab"),
             ports_dir: PathBuf::from("/usr/ports"),
+            ports_git: String::new(),
+            mirrors: BTreeMap::new(),
             repo_url: "https://github.com/scarab-os/packages/releases/download".to_string(),
+            repos: Vec::new(),
             arch: "x86_64".to_string(),
+            stale_days: 14,
+            jobs: 0,
+            parallel_extract: false,
+            downloader: "ureq".to_string(),
+            download_limit: String::new(),
+            hooks_dir: PathBuf::from("/etc/scarab/hooks.d"),
+            zstd_level: 19,
+            dedup: false,
+            rollback_keep: 0,
+            fsync: false,
+            verify_cache: false,
+            signing_key: None,
+            profiles: BTreeMap::new(),
         }
     }
 }
@@ -0,0 +1,123 @@
+use crate::db::InstalledPackage;
+use crate::verify::sha256_file;
+use anyhow::{Context, Result};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Suffix for a package's new version of a locally modified config file
+pub const NEW_SUFFIX: &str = ".scarabnew";
+/// Where a modified config file waits while its package is extracted
+const KEEP_SUFFIX: &str = ".scarab-keep";
+
+/// A locally modified config file moved out of the way of extraction
+pub struct SetAside {
+    path: String,
+    /// Hash the file had when its package was installed
+    original: String,
+}
+
+/// A modified config file that was put back after its package was extracted
+pub struct Kept {
+    pub path: String,
+    /// Hash of the version the package shipped
+    pub shipped: String,
+}
+
+fn with_suffix(root: &Path, path: &str, suffix: &str) -> PathBuf {
+    root.join(format!("{}{}", path, suffix))
+}
+
+/// Where the package's version of config file `path` is saved
+pub fn new_path(root: &Path, path: &str) -> PathBuf {
+    with_suffix(root, path, NEW_SUFFIX)
+}
+
+/// Whether installed path `path` (relative to the root) is a config file:
+/// anything under `etc/`
+pub fn is_config(path: &str) -> bool {
+    path.starts_with("etc/")
+}
+
+/// Move aside the config files (under `etc/`) of `installed` that were
+/// changed since it was installed, so extracting a new version can't
+/// overwrite them
+pub fn set_aside(root: &Path, installed: Option<&InstalledPackage>) -> Result<Vec<SetAside>> {
+    let Some(installed) = installed else {
+        return Ok(Vec::new());
+    };
+    let mut moved = Vec::new();
+    for (path, original) in &installed.file_hashes {
+        if !is_config(path) {
+            continue;
+        }
+        let current = root.join(path);
+        if !fs::symlink_metadata(&current).is_ok_and(|m| m.is_file())
+            || sha256_file(&current)? == *original
+        {
+            continue;
+        }
+        fs::rename(&current, with_suffix(root, path, KEEP_SUFFIX))
+            .with_context(|| format!("Failed to move aside /{}", path))?;
+        moved.push(SetAside {
+            path: path.clone(),
+            original: original.clone(),
+        });
+    }
+    Ok(moved)
+}
+
+/// Put set-aside config files back over whatever extraction wrote. If the
+/// package shipped a new version of one, that's saved as `<path>.scarabnew`
+/// for `scarab merge`; an unchanged version is simply dropped.
+pub fn restore(root: &Path, moved: Vec<SetAside>) -> Result<Vec<Kept>> {
+    let mut kept = Vec::new();
+    for entry in moved {
+        let current = root.join(&entry.path);
+        if fs::symlink_metadata(&current).is_ok() {
+            let shipped = sha256_file(&current)?;
+            if shipped == entry.original {
+                fs::remove_file(&current)?;
+            } else {
+                fs::rename(&current, new_path(root, &entry.path))?;
+                eprintln!(
+                    "  -> /{} was modified; new version saved as /{}{}",
+                    entry.path, entry.path, NEW_SUFFIX
+                );
+            }
+            kept.push(Kept {
+                path: entry.path.clone(),
+                shipped,
+            });
+        }
+        fs::rename(with_suffix(root, &entry.path, KEEP_SUFFIX), &current)
+            .with_context(|| format!("Failed to restore /{}", entry.path))?;
+    }
+    Ok(kept)
+}
+
+/// Config files with a `.scarabnew` version waiting to be merged, relative
+/// to root and sorted
+pub fn pending(root: &Path) -> Result<Vec<String>> {
+    let mut found = Vec::new();
+    let etc = root.join("etc");
+    if etc.is_dir() {
+        collect_pending(root, &etc, &mut found)?;
+    }
+    found.sort();
+    Ok(found)
+}
+
+fn collect_pending(root: &Path, dir: &Path, found: &mut Vec<String>) -> Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        if entry.file_type()?.is_dir() {
+            collect_pending(root, &path, found)?;
+        } else if let Some(rel) = path.strip_prefix(root).ok().and_then(|p| p.to_str())
+            && let Some(original) = rel.strip_suffix(NEW_SUFFIX)
+        {
+            found.push(original.to_string());
+        }
+    }
+    Ok(())
+}
@@ -1,9 +1,14 @@
 use crate::config::Config;
+use crate::disk;
+use crate::error::Error;
+use crate::timestamp;
+use crate::version;
 use anyhow::{Context, Result};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::fs;
-use std::path::PathBuf;
+use std::io::{BufRead, BufReader};
+use std::path::{Path, PathBuf};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct PackageInfo {
@@ -15,14 +20,160 @@ pub struct PackageInfo {
     pub size: String,
     pub sha256: String,
     pub filename: String,
+    /// Target architecture; empty or "any" matches every arch
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub arch: String,
+    /// Repository the package was loaded from (not part of repo.json)
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub repo: String,
+    /// Named bundles this package belongs to, installable as `@group`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub groups: Vec<String>,
+    /// Meta-package: only pulls in its dependencies, with no tarball of its own
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub meta: bool,
+    /// Virtual names this package satisfies as a dependency
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub provides: Vec<String>,
+    /// Weak dependencies: installed only with `--install-recommends`, and
+    /// never an error if missing
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub recommends: Vec<String>,
+    /// Where to download the tarball from, used verbatim instead of the
+    /// repo's `{url}/v{version}/{filename}` layout (e.g. an upstream release)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+    /// Detached minisign signature of the tarball, downloaded from next to it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sig_filename: Option<String>,
+}
+
+impl PackageInfo {
+    pub fn matches_arch(&self, arch: &str) -> bool {
+        self.arch.is_empty() || self.arch == "any" || self.arch == arch
+    }
+
+    /// Whether there is nothing to download, either flagged `meta` or with no filename
+    pub fn is_meta(&self) -> bool {
+        self.meta || self.filename.is_empty()
+    }
+}
+
+/// What removing packages would affect, from [`Database::removal_impact`]
+pub struct RemovalImpact {
+    /// Installed packages left with an unsatisfied dependency, directly or
+    /// because a package they need would break, each with the dependency
+    pub dependents: Vec<(String, String)>,
+    /// Installed dependencies that nothing would need any more
+    pub orphans: Vec<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct InstalledPackage {
     pub name: String,
     pub version: String,
+    /// RFC 3339 UTC time of the install (older databases: local `date` output)
     pub installed_at: String,
     pub files: Vec<String>,
+    /// Installed size from the repo entry at install time
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub size: String,
+    /// SHA256 of each regular file at install time, for `scarab verify`
+    #[serde(default)]
+    pub file_hashes: BTreeMap<String, String>,
+    /// Pulled in only to satisfy another package, not asked for by name
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub dependency: bool,
+    /// Root the files were installed into, when a repo's `root` sent them
+    /// somewhere other than the configured root
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub root: Option<PathBuf>,
+    /// SHA256 of the tarball installed, to spot same-version rebuilds
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub sha256: String,
+}
+
+impl InstalledPackage {
+    /// Seconds since the Unix epoch when this package was installed, if known
+    pub fn installed_secs(&self) -> Option<u64> {
+        timestamp::parse(&self.installed_at)
+    }
+
+    /// Its files and symlinks, leaving out the directories recorded with a
+    /// trailing `/`
+    pub fn file_entries(&self) -> impl Iterator<Item = &String> {
+        self.files.iter().filter(|f| !f.ends_with('/'))
+    }
+
+    /// Root this package's files live under
+    pub fn root<'a>(&'a self, cfg: &'a Config) -> &'a Path {
+        self.root.as_deref().unwrap_or(&cfg.root)
+    }
+
+    /// Installed size in bytes: the repo's figure if one was recorded, else
+    /// the total size of its files under `root` as they are now
+    pub fn installed_size(&self, root: &Path) -> u64 {
+        disk::parse_size(&self.size).unwrap_or_else(|| {
+            self.files
+                .iter()
+                .filter_map(|f| fs::symlink_metadata(root.join(f)).ok())
+                .filter(|m| m.is_file())
+                .map(|m| m.len())
+                .sum()
+        })
+    }
+}
+
+/// A resolved install: what to install, and what couldn't be found
+pub struct InstallPlan {
+    /// Packages to install, dependencies first
+    pub packages: Vec<PackageInfo>,
+    /// Dependencies nothing in the repos satisfies, each with the packages
+    /// that need it
+    pub unresolved: BTreeMap<String, Vec<String>>,
+    /// Each decision the resolver made, indented by depth, for `--explain`
+    pub trace: Vec<String>,
+}
+
+/// Working state of one `install_plan` pass
+#[derive(Default)]
+struct PlanWalk {
+    plan: Vec<PackageInfo>,
+    visited: HashSet<String>,
+    /// What each dependency resolved to, including to nothing
+    resolved: HashMap<String, Option<String>>,
+    unresolved: BTreeMap<String, Vec<String>>,
+    trace: Vec<String>,
+    depth: usize,
+}
+
+impl PlanWalk {
+    fn note(&mut self, line: String) {
+        self.trace.push(format!("{}{}", "  ".repeat(self.depth), line));
+    }
+}
+
+/// One package in a dependency closure
+#[derive(Debug)]
+pub struct Dependency {
+    pub name: String,
+    /// The virtual dependency this package was picked to provide, if any
+    pub provides: Option<String>,
+    /// Repo version, or None if the repo doesn't have it
+    pub available: Option<String>,
+    /// Installed version, if any
+    pub installed: Option<String>,
+}
+
+/// One step of an exported install plan
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PlanEntry {
+    pub name: String,
+    pub version: String,
+    pub sha256: String,
+    /// Pulled in for another entry rather than asked for by name
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub dependency: bool,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -31,72 +182,233 @@ pub struct Database {
     config: Option<Config>,
     pub packages: Vec<PackageInfo>,
     pub installed: HashMap<String, InstalledPackage>,
+    /// Every architecture the repo carries packages for
+    #[serde(skip)]
+    pub arches: BTreeSet<String>,
+    /// Dependencies treated as satisfied without being installed
+    #[serde(skip)]
+    assumed: HashSet<String>,
+    /// Exact versions forced for specific packages (`db_dir/pins.json`)
+    #[serde(skip)]
+    pub pins: HashMap<String, String>,
+    /// Repo that explicitly named packages must come from (`--repo`)
+    #[serde(skip)]
+    target_repo: Option<String>,
+    /// Provider picked for each virtual dependency with several candidates
+    #[serde(skip)]
+    providers: HashMap<String, String>,
+    /// Pull recommended packages into install plans (`--install-recommends`)
+    #[serde(skip)]
+    with_recommends: bool,
 }
 
 impl Database {
     pub fn load(cfg: &Config) -> Result<Self> {
-        let repo_db_path = cfg.db_dir.join("repo.json");
-        let installed_db_path = cfg.db_dir.join("installed.json");
+        let mut packages = Vec::new();
+        for repo in cfg.repos() {
+            let mut repo_packages = load_repo_db(cfg, &repo.name)?;
+            for pkg in &mut repo_packages {
+                pkg.repo = repo.name.clone();
+            }
+            packages.extend(repo_packages);
+        }
+        let arches = packages
+            .iter()
+            .filter(|p| !p.arch.is_empty() && p.arch != "any")
+            .map(|p| p.arch.clone())
+            .collect();
+        packages.retain(|p| p.matches_arch(&cfg.arch));
 
-        let packages: Vec<PackageInfo> = if repo_db_path.exists() {
-            let content = fs::read_to_string(&repo_db_path)?;
-            serde_json::from_str(&content)?
-        } else {
-            Vec::new()
-        };
+        let installed = load_installed(&cfg.db_dir)?;
 
-        let installed: HashMap<String, InstalledPackage> = if installed_db_path.exists() {
-            let content = fs::read_to_string(&installed_db_path)?;
-            serde_json::from_str(&content)?
+        let pins_path = cfg.db_dir.join("pins.json");
+        let pins: HashMap<String, String> = if pins_path.exists() {
+            let content = fs::read_to_string(&pins_path)?;
+            serde_json::from_str(&content)
+                .with_context(|| format!("Invalid {}", pins_path.display()))?
         } else {
             HashMap::new()
         };
 
         Ok(Self {
-            config: Some(Config {
-                root: cfg.root.clone(),
-                db_dir: cfg.db_dir.clone(),
-                cache_dir: cfg.cache_dir.clone(),
-                ports_dir: cfg.ports_dir.clone(),
-                repo_url: cfg.repo_url.clone(),
-                arch: cfg.arch.clone(),
-            }),
+            config: Some(cfg.clone()),
             packages,
             installed,
+            arches,
+            assumed: HashSet::new(),
+            pins,
+            target_repo: None,
+            providers: HashMap::new(),
+            with_recommends: false,
         })
     }
 
     pub fn save(&self) -> Result<()> {
         let cfg = self.config.as_ref().unwrap();
-        fs::create_dir_all(&cfg.db_dir)?;
-
-        let repo_json = serde_json::to_string_pretty(&self.packages)?;
-        fs::write(cfg.db_dir.join("repo.json"), repo_json)?;
+        let installed_path = cfg.db_dir.join("installed.json");
 
         let installed_json = serde_json::to_string_pretty(&self.installed)?;
-        fs::write(cfg.db_dir.join("installed.json"), installed_json)?;
+        fs::create_dir_all(&cfg.db_dir)
+            .and_then(|_| fs::write(&installed_path, installed_json))
+            .map_err(|e| match e.kind() {
+                std::io::ErrorKind::PermissionDenied => Error::Permission(format!(
+                    "Cannot write {} (are you root?)",
+                    installed_path.display()
+                ))
+                .into(),
+                _ => anyhow::Error::from(e),
+            })?;
 
         Ok(())
     }
 
     pub fn find_package(&self, name: &str) -> Result<PackageInfo> {
+        self.lookup(name).cloned().ok_or_else(|| {
+            Error::NotFound(format!("Package '{}' not found. Run 'scarab sync' first?", name))
+                .into()
+        })
+    }
+
+    /// The entry to use for `name`: the pinned version if the repo has it,
+    /// otherwise the newest version in the first repo that has the package
+    pub fn lookup(&self, name: &str) -> Option<&PackageInfo> {
+        if let Some(pinned) = self.pins.get(name)
+            && let Some(pkg) = self.find_version(name, pinned)
+        {
+            return Some(pkg);
+        }
+        let first = self.packages.iter().find(|p| p.name == name)?;
+        self.newest_in(&first.repo, name)
+    }
+
+    /// The newest version of `name` that repo `repo` carries
+    pub fn newest_in(&self, repo: &str, name: &str) -> Option<&PackageInfo> {
         self.packages
             .iter()
-            .find(|p| p.name == name)
-            .cloned()
-            .with_context(|| format!("Package '{}' not found. Run 'scarab sync' first?", name))
+            .filter(|p| p.name == name && p.repo == repo)
+            .max_by(|a, b| version::compare(&a.version, &b.version))
+    }
+
+    /// Every version of `name` in the synced repos, newest first (in repo
+    /// priority order for the same version)
+    pub fn versions(&self, name: &str) -> Vec<&PackageInfo> {
+        let mut versions: Vec<_> = self.packages.iter().filter(|p| p.name == name).collect();
+        versions.sort_by(|a, b| version::compare(&b.version, &a.version));
+        versions
+    }
+
+    /// Use exactly `version` of `name` for this run, as if pinned. Fails if
+    /// the repo doesn't carry that version or a pin asks for another one.
+    pub fn request_version(&mut self, name: &str, version: &str) -> Result<()> {
+        let available: Vec<_> = self
+            .packages
+            .iter()
+            .filter(|p| p.name == name)
+            .map(|p| p.version.as_str())
+            .collect();
+        if available.is_empty() {
+            self.find_package(name)?;
+        }
+        if !available.contains(&version) {
+            return Err(Error::NotFound(format!(
+                "{} {} is not in the repo (available: {})",
+                name,
+                version,
+                available.join(", ")
+            ))
+            .into());
+        }
+        if let Some(pinned) = self.pins.get(name)
+            && pinned != version
+        {
+            return Err(Error::Conflict(format!("{} is pinned to {}", name, pinned)).into());
+        }
+        self.pins.insert(name.to_string(), version.to_string());
+        Ok(())
+    }
+
+    fn check_pin(&self, pkg: &PackageInfo) -> Result<()> {
+        match self.pins.get(&pkg.name) {
+            Some(pinned) if *pinned != pkg.version => Err(Error::Conflict(format!(
+                "{} is pinned to {} but the repo has {}",
+                pkg.name, pinned, pkg.version
+            ))
+            .into()),
+            _ => Ok(()),
+        }
+    }
+
+    /// The entries of a `depends` list that apply here: plain ones, and
+    /// conditional `cond?dep` ones whose `cond` package is installed
+    pub fn active_depends(&self, depends: &[String]) -> Vec<String> {
+        depends
+            .iter()
+            .filter_map(|entry| match split_depend(entry) {
+                (Some(cond), dep) => self.is_satisfied(cond).then(|| dep.to_string()),
+                (None, dep) => Some(dep.to_string()),
+            })
+            .collect()
     }
 
     pub fn get_installed(&self, name: &str) -> Option<&InstalledPackage> {
         self.installed.get(name)
     }
 
+    /// Treat these packages as installed when resolving dependencies.
+    /// Entries may carry a version (`name=version`), which is ignored.
+    pub fn assume_installed(&mut self, specs: &[String]) {
+        for spec in specs {
+            let name = spec.split_once('=').map_or(spec.as_str(), |(n, _)| n);
+            self.assumed.insert(name.to_string());
+        }
+    }
+
+    /// Also plan the packages that planned packages recommend
+    pub fn install_recommends(&mut self) {
+        self.with_recommends = true;
+    }
+
+    /// Whether a dependency needs no action: installed or assumed installed
+    pub fn is_satisfied(&self, name: &str) -> bool {
+        self.installed.contains_key(name) || self.assumed.contains(name)
+    }
+
     pub fn list_installed(&self) -> Vec<&InstalledPackage> {
         let mut list: Vec<_> = self.installed.values().collect();
         list.sort_by(|a, b| a.name.cmp(&b.name));
         list
     }
 
+    /// Paths owned by installed packages other than `pkg` that live under
+    /// the same root as it
+    pub fn files_owned_by_others(&self, cfg: &Config, pkg: &InstalledPackage) -> HashSet<String> {
+        let root = pkg.root(cfg);
+        self.installed
+            .values()
+            .filter(|p| p.name != pkg.name && p.root(cfg) == root)
+            .flat_map(|p| p.files.iter().map(|f| f.trim_end_matches('/').to_string()))
+            .collect()
+    }
+
+    /// Installed packages with no matching repo entry (locally built or dropped)
+    pub fn foreign_packages(&self) -> Vec<&InstalledPackage> {
+        self.list_installed()
+            .into_iter()
+            .filter(|i| !self.packages.iter().any(|p| p.name == i.name))
+            .collect()
+    }
+
+    /// Repo packages that belong to `group`, sorted by name
+    pub fn group_members(&self, group: &str) -> Vec<&PackageInfo> {
+        let mut members: Vec<_> = self
+            .packages
+            .iter()
+            .filter(|p| p.groups.iter().any(|g| g == group))
+            .collect();
+        members.sort_by(|a, b| a.name.cmp(&b.name));
+        members
+    }
+
     pub fn search(&self, query: &str) -> Vec<&PackageInfo> {
         let q = query.to_lowercase();
         self.packages
@@ -107,79 +419,703 @@ impl Database {
             .collect()
     }
 
-    pub fn resolve_deps(&self, pkg: &PackageInfo) -> Result<Vec<String>> {
-        let mut deps = Vec::new();
-        let mut visited = Vec::new();
-        self.resolve_deps_recursive(pkg, &mut deps, &mut visited)?;
-        Ok(deps)
+    /// Consistency problems in the synced repo databases, as (package, problem)
+    pub fn check_repo(&self) -> Vec<(String, String)> {
+        let mut issues = Vec::new();
+        let mut seen = HashSet::new();
+        for pkg in &self.packages {
+            let label = if pkg.repo.is_empty() {
+                pkg.name.clone()
+            } else {
+                format!("{}/{}", pkg.repo, pkg.name)
+            };
+            let mut issue = |problem: String| issues.push((label.clone(), problem));
+
+            if !seen.insert((pkg.repo.as_str(), pkg.name.as_str(), pkg.version.as_str())) {
+                issue("listed more than once".to_string());
+            }
+            if !version::is_valid(&pkg.version) {
+                issue(format!("invalid version '{}'", pkg.version));
+            }
+            for entry in &pkg.depends {
+                let (cond, dep) = split_depend(entry);
+                let known = |name: &str| {
+                    self.packages
+                        .iter()
+                        .any(|p| p.name == name || p.provides.iter().any(|v| v == name))
+                };
+                if !known(dep) {
+                    issue(format!("depends on unknown package '{}'", dep));
+                }
+                if cond == Some("") {
+                    issue(format!("empty condition in dependency '{}'", entry));
+                }
+            }
+            if !pkg.is_meta() {
+                if pkg.sha256.len() != 64 || !pkg.sha256.bytes().all(|b| b.is_ascii_hexdigit()) {
+                    issue(format!("invalid sha256 '{}'", pkg.sha256));
+                }
+                if pkg.size.is_empty() {
+                    issue("no size".to_string());
+                } else if disk::parse_size(&pkg.size).is_none() {
+                    issue(format!("invalid size '{}'", pkg.size));
+                }
+            }
+        }
+        issues
     }
 
-    fn resolve_deps_recursive(
+    /// Take packages from `repo` ahead of the configured priority order, and
+    /// require the packages named for install to come from it
+    pub fn prefer_repo(&mut self, repo: &str) -> Result<()> {
+        if !self.packages.iter().any(|p| p.repo == repo) {
+            return Err(Error::NotFound(format!(
+                "No packages from repo '{}' (is it configured and synced?)",
+                repo
+            ))
+            .into());
+        }
+        self.packages.sort_by_key(|p| p.repo != repo);
+        self.target_repo = Some(repo.to_string());
+        Ok(())
+    }
+
+    /// Every direct and indirect dependency of `pkg`, dependencies first,
+    /// whether installed or not (unlike `install_plan`, which skips the
+    /// installed ones)
+    pub fn dependency_closure(&self, pkg: &PackageInfo) -> Vec<Dependency> {
+        let mut closure = Vec::new();
+        let mut visited = HashSet::from([pkg.name.clone()]);
+        self.closure_visit(pkg, &mut closure, &mut visited);
+        closure
+    }
+
+    fn closure_visit(
         &self,
         pkg: &PackageInfo,
-        deps: &mut Vec<String>,
-        visited: &mut Vec<String>,
+        closure: &mut Vec<Dependency>,
+        visited: &mut HashSet<String>,
+    ) {
+        for dep in &self.active_depends(&pkg.depends) {
+            // A virtual dependency stands for its provider, as in `install_plan`
+            let name = self.resolve_dep(dep).unwrap_or_else(|| dep.clone());
+            if !visited.insert(name.clone()) {
+                continue;
+            }
+            let repo_pkg = self.find_package(&name).ok();
+            if let Some(repo_pkg) = &repo_pkg {
+                self.closure_visit(repo_pkg, closure, visited);
+            }
+            closure.push(Dependency {
+                provides: (name != *dep).then(|| dep.clone()),
+                available: repo_pkg.map(|p| p.version),
+                installed: self.get_installed(&name).map(|p| p.version.clone()),
+                name,
+            });
+        }
+    }
+
+    /// Repo packages that provide the virtual name `name`, one per package
+    /// name, in repo priority order
+    pub fn providers(&self, name: &str) -> Vec<&PackageInfo> {
+        let mut seen = HashSet::new();
+        self.packages
+            .iter()
+            .filter(|p| p.provides.iter().any(|v| v == name) && seen.insert(&p.name))
+            .collect()
+    }
+
+    /// The repo package that satisfies dependency `dep`: a package of that
+    /// name, else an installed provider, the chosen one, or the first
+    fn resolve_dep(&self, dep: &str) -> Option<String> {
+        if self.find_package(dep).is_ok() {
+            return Some(dep.to_string());
+        }
+        let providers = self.providers(dep);
+        providers
+            .iter()
+            .find(|p| self.is_satisfied(&p.name))
+            .or_else(|| {
+                let chosen = self.providers.get(dep)?;
+                providers.iter().find(|p| p.name == *chosen)
+            })
+            .or(providers.first())
+            .map(|p| p.name.clone())
+    }
+
+    /// Walk the dependencies of `names` and ask `choose` (given the virtual
+    /// name and its candidates) which package should provide each virtual
+    /// dependency that several repo packages provide and none installed does.
+    /// `install_plan` then uses those picks.
+    pub fn choose_providers(
+        &mut self,
+        names: &[&str],
+        choose: &mut dyn FnMut(&str, &[&PackageInfo]) -> Result<usize>,
     ) -> Result<()> {
-        for dep_name in &pkg.depends {
-            if visited.contains(dep_name) {
+        let mut stack: Vec<String> = names.iter().map(|n| n.to_string()).collect();
+        let mut visited = HashSet::new();
+        while let Some(name) = stack.pop() {
+            if !visited.insert(name.clone()) {
                 continue;
             }
-            visited.push(dep_name.clone());
+            let Ok(pkg) = self.find_package(&name) else { continue };
+            for dep in &self.active_depends(&pkg.depends) {
+                if self.assumed.contains(dep) {
+                    continue;
+                }
+                let candidates = self.providers(dep);
+                if self.find_package(dep).is_err()
+                    && candidates.len() > 1
+                    && !candidates.iter().any(|p| self.is_satisfied(&p.name))
+                    && !self.providers.contains_key(dep)
+                {
+                    let chosen = candidates[choose(dep, &candidates)?].name.clone();
+                    self.providers.insert(dep.clone(), chosen);
+                }
+                stack.extend(self.resolve_dep(dep));
+            }
+        }
+        Ok(())
+    }
+
+    /// Every package needed to install `names`, in dependency order: the
+    /// targets themselves plus any missing dependencies. A target that another
+    /// target depends on is placed before it, so upgrades apply bottom-up.
+    /// Dependencies nothing satisfies are left out of the plan and reported
+    /// in `unresolved`.
+    pub fn install_plan(&self, names: &[&str]) -> Result<InstallPlan> {
+        let mut walk = PlanWalk::default();
+        for name in names {
+            if let Some(repo) = &self.target_repo
+                && self.find_package(name)?.repo != *repo
+            {
+                return Err(Error::NotFound(format!("{} is not in repo '{}'", name, repo)).into());
+            }
+            let choice = self.describe_choice(name, names);
+            walk.note(format!("{} requested; {}", name, choice));
+            self.plan_visit(name, names, &mut walk)?;
+        }
+        Ok(InstallPlan {
+            packages: walk.plan,
+            unresolved: walk.unresolved,
+            trace: walk.trace,
+        })
+    }
+
+    /// How `install_plan` deals with package `name`, for its trace
+    fn describe_choice(&self, name: &str, targets: &[&str]) -> String {
+        if let Some(installed) = self.get_installed(name)
+            && !targets.contains(&name)
+        {
+            return format!("already satisfied by installed {}", installed.version);
+        }
+        match self.lookup(name) {
+            Some(pkg) if self.pins.contains_key(name) => {
+                format!("selected {} {} from repo {} (pinned)", name, pkg.version, pkg.repo)
+            }
+            Some(pkg) => format!("selected {} {} from repo {}", name, pkg.version, pkg.repo),
+            None => "not in any repo".to_string(),
+        }
+    }
+
+    /// `resolve_dep` for one resolution pass, looking each dependency up
+    /// only once
+    fn resolve_cached(&self, dep: &str, walk: &mut PlanWalk) -> Option<String> {
+        if let Some(resolved) = walk.resolved.get(dep) {
+            return resolved.clone();
+        }
+        let resolved = self.resolve_dep(dep);
+        walk.resolved.insert(dep.to_string(), resolved.clone());
+        resolved
+    }
 
-            if let Ok(dep_pkg) = self.find_package(dep_name) {
-                self.resolve_deps_recursive(&dep_pkg, deps, visited)?;
+    fn plan_visit(&self, name: &str, targets: &[&str], walk: &mut PlanWalk) -> Result<()> {
+        if !walk.visited.insert(name.to_string()) {
+            return Ok(());
+        }
+        let pkg = self.find_package(name)?;
 
-                if self.get_installed(dep_name).is_none() && !deps.contains(dep_name) {
-                    deps.push(dep_name.clone());
+        // Installed deps are walked too, in case they need something missing
+        for dep in &self.active_depends(&pkg.depends) {
+            if self.assumed.contains(dep) {
+                walk.note(format!("{} requires {}; assumed installed", name, dep));
+                continue;
+            }
+            match self.resolve_cached(dep, walk) {
+                Some(provider) => {
+                    let choice = self.describe_choice(&provider, targets);
+                    if provider == *dep {
+                        walk.note(format!("{} requires {}; {}", name, dep, choice));
+                    } else {
+                        walk.note(format!(
+                            "{} requires {}; provided by {}, {}",
+                            name, dep, provider, choice
+                        ));
+                    }
+                    walk.depth += 1;
+                    self.plan_visit(&provider, targets, walk)?;
+                    walk.depth -= 1;
+                }
+                None if !self.is_satisfied(dep) => {
+                    walk.note(format!("{} requires {}; nothing provides it", name, dep));
+                    walk.unresolved.entry(dep.clone()).or_default().push(name.to_string());
+                }
+                None => {
+                    walk.note(format!("{} requires {}; satisfied by installed {}", name, dep, dep))
                 }
             }
         }
+
+        // Without --install-recommends, recommendations only order the plan.
+        // One that can't be installed, or would bring in unresolvable
+        // dependencies, is skipped rather than failing it.
+        for rec in &self.active_depends(&pkg.recommends) {
+            let Some(rec) = self.resolve_cached(rec, walk) else { continue };
+            if !self.with_recommends && !targets.contains(&rec.as_str()) {
+                walk.note(format!("{} recommends {}; not planned", name, rec));
+                continue;
+            }
+            let choice = self.describe_choice(&rec, targets);
+            walk.note(format!("{} recommends {}; {}", name, rec, choice));
+            let planned = walk.plan.len();
+            let (seen, unresolved) = (walk.visited.clone(), walk.unresolved.clone());
+            walk.depth += 1;
+            let result = self.plan_visit(&rec, targets, walk);
+            walk.depth -= 1;
+            let result = result.and_then(|()| {
+                if walk.unresolved == unresolved {
+                    Ok(())
+                } else {
+                    Err(Error::NotFound("it has unresolvable dependencies".to_string()).into())
+                }
+            });
+            if let Err(e) = result {
+                walk.plan.truncate(planned);
+                walk.visited = seen;
+                walk.unresolved = unresolved;
+                eprintln!("  -> Skipping {} (recommended by {}): {:#}", rec, name, e);
+            }
+        }
+
+        if targets.contains(&name) || self.get_installed(name).is_none() {
+            self.check_pin(&pkg)?;
+            walk.plan.push(pkg);
+        }
         Ok(())
     }
 
+    /// Installed dependencies of `plan` that the repo has a newer version of
+    /// but the plan leaves alone, as (name, installed, available). Installing
+    /// against them risks version skew (a partial upgrade).
+    pub fn outdated_deps(&self, plan: &[PackageInfo]) -> Vec<(String, String, String)> {
+        let mut outdated = BTreeMap::new();
+        for pkg in plan {
+            for dep in &self.active_depends(&pkg.depends) {
+                let Some(dep) = self.resolve_dep(dep) else { continue };
+                if plan.iter().any(|p| p.name == dep) {
+                    continue;
+                }
+                if let (Some(installed), Some(repo_pkg)) =
+                    (self.get_installed(&dep), self.lookup(&dep))
+                    && version::compare(&repo_pkg.version, &installed.version).is_gt()
+                {
+                    outdated.insert(dep, (installed.version.clone(), repo_pkg.version.clone()));
+                }
+            }
+        }
+        outdated
+            .into_iter()
+            .map(|(name, (installed, available))| (name, installed, available))
+            .collect()
+    }
+
+    /// Split a dependency-ordered plan into levels, each holding the plan
+    /// positions of packages that don't depend on one another. Installing
+    /// level by level keeps every package after its dependencies.
+    pub fn plan_levels(&self, plan: &[PackageInfo]) -> Vec<Vec<usize>> {
+        let mut level_of: HashMap<&str, usize> = HashMap::new();
+        let mut levels: Vec<Vec<usize>> = Vec::new();
+        for (i, pkg) in plan.iter().enumerate() {
+            let level = self
+                .active_depends(&pkg.depends)
+                .iter()
+                .filter_map(|dep| self.resolve_dep(dep))
+                .filter_map(|dep| level_of.get(dep.as_str()).map(|l| l + 1))
+                .max()
+                .unwrap_or(0);
+            level_of.insert(&pkg.name, level);
+            if levels.len() <= level {
+                levels.resize_with(level + 1, Vec::new);
+            }
+            levels[level].push(i);
+        }
+        levels
+    }
+
+    /// Packages recommended by `plan` entries that aren't installed and won't
+    /// be, with the package recommending each
+    pub fn skipped_recommends(&self, plan: &[PackageInfo]) -> Vec<(String, String)> {
+        let mut skipped = BTreeMap::new();
+        for pkg in plan {
+            for rec in &self.active_depends(&pkg.recommends) {
+                let Some(rec) = self.resolve_dep(rec) else { continue };
+                if !self.is_satisfied(&rec) && !plan.iter().any(|p| p.name == rec) {
+                    skipped.entry(rec).or_insert_with(|| pkg.name.clone());
+                }
+            }
+        }
+        skipped.into_iter().collect()
+    }
+
+    /// Dependencies of installed packages that nothing installed satisfies,
+    /// as (package, dependency). Packages the repo no longer carries are
+    /// skipped, since their dependencies aren't known.
+    pub fn missing_installed_deps(&self) -> Vec<(String, String)> {
+        let mut missing = Vec::new();
+        for installed in self.list_installed() {
+            let Some(pkg) = self.find_version(&installed.name, &installed.version) else {
+                continue;
+            };
+            for dep in self.active_depends(&pkg.depends) {
+                let provided = self.providers(&dep).iter().any(|p| self.is_satisfied(&p.name));
+                if !self.is_satisfied(&dep) && !provided {
+                    missing.push((installed.name.clone(), dep));
+                }
+            }
+        }
+        missing
+    }
+
+    /// For each dependency of installed package `name`, the installed
+    /// packages that satisfy it: the package itself, or its providers. Empty
+    /// when the repo no longer carries the installed version.
+    fn installed_deps(&self, name: &str) -> Vec<Vec<String>> {
+        let Some(pkg) = self
+            .get_installed(name)
+            .and_then(|installed| self.find_version(name, &installed.version))
+        else {
+            return Vec::new();
+        };
+        self.active_depends(&pkg.depends)
+            .into_iter()
+            .map(|dep| {
+                if self.installed.contains_key(&dep) {
+                    return vec![dep];
+                }
+                self.providers(&dep)
+                    .into_iter()
+                    .filter(|p| self.installed.contains_key(&p.name))
+                    .map(|p| p.name.clone())
+                    .collect()
+            })
+            .filter(|satisfiers: &Vec<String>| !satisfiers.is_empty())
+            .collect()
+    }
+
+    /// What removing the installed packages `names` would do to the rest:
+    /// which packages would lose a dependency, and which dependencies nothing
+    /// would need any more
+    pub fn removal_impact(&self, names: &[String]) -> RemovalImpact {
+        let installed: Vec<_> = self.list_installed().into_iter().map(|p| &p.name).collect();
+        let deps: HashMap<&str, Vec<Vec<String>>> =
+            installed.iter().map(|&name| (name.as_str(), self.installed_deps(name))).collect();
+
+        // Dependents break once every satisfier of one of their dependencies
+        // is gone, which can in turn break their own dependents
+        let mut gone: HashSet<&str> = names.iter().map(String::as_str).collect();
+        let mut dependents = Vec::new();
+        loop {
+            let broken: Vec<_> = installed
+                .iter()
+                .filter(|name| !gone.contains(name.as_str()))
+                .filter_map(|&name| {
+                    let lost = deps[name.as_str()]
+                        .iter()
+                        .find(|sats| sats.iter().all(|s| gone.contains(s.as_str())))?;
+                    Some((name.clone(), lost[0].clone()))
+                })
+                .collect();
+            if broken.is_empty() {
+                break;
+            }
+            for (name, needs) in broken {
+                gone.insert(self.installed[&name].name.as_str());
+                dependents.push((name, needs));
+            }
+        }
+
+        // Orphans: dependencies of the removed packages, installed only as
+        // dependencies, that no remaining package needs
+        let mut removed: HashSet<&str> = names.iter().map(String::as_str).collect();
+        let mut candidates = HashSet::new();
+        let mut queue: Vec<&str> = removed.iter().copied().collect();
+        while let Some(name) = queue.pop() {
+            for dep in deps.get(name).into_iter().flatten().flatten() {
+                if candidates.insert(dep.as_str()) {
+                    queue.push(dep);
+                }
+            }
+        }
+        let mut orphans = Vec::new();
+        loop {
+            let needed = |name: &str| {
+                installed.iter().any(|other| {
+                    !removed.contains(other.as_str())
+                        && deps[other.as_str()].iter().flatten().any(|d| d == name)
+                })
+            };
+            let freed: Vec<_> = installed
+                .iter()
+                .map(|name| name.as_str())
+                .filter(|name| candidates.contains(name) && !removed.contains(name))
+                .filter(|name| self.installed[*name].dependency && !needed(name))
+                .collect();
+            if freed.is_empty() {
+                break;
+            }
+            for name in freed {
+                removed.insert(name);
+                orphans.push(name.to_string());
+            }
+        }
+
+        RemovalImpact { dependents, orphans }
+    }
+
+    /// The repo's entry for exactly `name` at `version`, if it still has one
+    pub fn find_version(&self, name: &str, version: &str) -> Option<&PackageInfo> {
+        self.packages
+            .iter()
+            .find(|p| p.name == name && p.version == version)
+    }
+
+    /// Read an exported plan, failing if any exact version it names is gone
+    /// from the repo or no longer matches its recorded checksum. Also returns
+    /// the names the plan was exported for.
+    pub fn load_plan(&self, path: &Path) -> Result<(Vec<PackageInfo>, Vec<String>)> {
+        let content = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read plan {}", path.display()))?;
+        let entries: Vec<PlanEntry> = serde_json::from_str(&content)
+            .with_context(|| format!("Invalid plan {}", path.display()))?;
+
+        let plan = entries
+            .iter()
+            .map(|entry| {
+                let pkg = self
+                    .find_version(&entry.name, &entry.version)
+                    .ok_or_else(|| {
+                        Error::NotFound(format!(
+                            "{} {} from the plan is no longer available",
+                            entry.name, entry.version
+                        ))
+                    })?;
+                if pkg.sha256 != entry.sha256 {
+                    return Err(Error::Verification(format!(
+                        "{} {} has a different checksum than when the plan was exported",
+                        entry.name, entry.version
+                    ))
+                    .into());
+                }
+                Ok(pkg.clone())
+            })
+            .collect::<Result<_>>()?;
+        let requested = entries
+            .into_iter()
+            .filter(|entry| !entry.dependency)
+            .map(|entry| entry.name)
+            .collect();
+        Ok((plan, requested))
+    }
+
+    /// Installed packages the repo has a newer version of, as (name, installed,
+    /// available). A pinned package instead moves to its pin, in either direction.
     pub fn check_upgrades(&self) -> Vec<(String, String, String)> {
         let mut upgrades = Vec::new();
         for (name, installed) in &self.installed {
-            if let Some(repo_pkg) = self.packages.iter().find(|p| &p.name == name) {
-                if repo_pkg.version != installed.version {
-                    upgrades.push((
-                        name.clone(),
-                        installed.version.clone(),
-                        repo_pkg.version.clone(),
-                    ));
+            if let Some(repo_pkg) = self.lookup(name)
+                && match self.pins.get(name) {
+                    Some(pinned) => *pinned == repo_pkg.version && *pinned != installed.version,
+                    None => version::compare(&repo_pkg.version, &installed.version).is_gt(),
                 }
+            {
+                upgrades.push((
+                    name.clone(),
+                    installed.version.clone(),
+                    repo_pkg.version.clone(),
+                ));
             }
         }
         upgrades
     }
 
-    pub fn record_install(&mut self, pkg: &PackageInfo) -> Result<()> {
-        let now = chrono_now();
+    /// Installed packages the repo still carries at the same version but
+    /// with a different checksum, i.e. rebuilt without a version bump, as
+    /// (name, version). Packages installed without a recorded checksum are
+    /// never reported.
+    pub fn check_rebuilds(&self) -> Vec<(String, String)> {
+        let mut rebuilt: Vec<_> = self
+            .installed
+            .values()
+            .filter(|installed| {
+                !installed.sha256.is_empty()
+                    && self
+                        .find_version(&installed.name, &installed.version)
+                        .is_some_and(|p| !p.sha256.is_empty() && p.sha256 != installed.sha256)
+            })
+            .map(|installed| (installed.name.clone(), installed.version.clone()))
+            .collect();
+        rebuilt.sort();
+        rebuilt
+    }
+
+    /// Record `pkg` as installed. `explicit` marks it as asked for by name;
+    /// otherwise an upgrade or reinstall keeps its install reason and a new
+    /// package counts as a dependency.
+    pub fn record_install(
+        &mut self,
+        pkg: &PackageInfo,
+        files: Vec<String>,
+        file_hashes: BTreeMap<String, String>,
+        explicit: bool,
+    ) -> Result<()> {
+        let now = timestamp::now();
+        let dependency = !explicit && self.installed.get(&pkg.name).is_none_or(|p| p.dependency);
+        let root = self.config.as_ref().and_then(|cfg| {
+            let root = cfg.root_for(&pkg.repo);
+            (root != cfg.root).then(|| root.to_path_buf())
+        });
         self.installed.insert(
             pkg.name.clone(),
             InstalledPackage {
                 name: pkg.name.clone(),
                 version: pkg.version.clone(),
                 installed_at: now,
-                files: Vec::new(), // TODO: track files from tar
+                files,
+                size: pkg.size.clone(),
+                file_hashes,
+                dependency,
+                root,
+                sha256: pkg.sha256.clone(),
             },
         );
         self.save()
     }
 
+    /// Record whether installed packages `names` were pulled in as
+    /// dependencies or asked for by name
+    pub fn mark_dependency(&mut self, names: &[&str], dependency: bool) -> Result<()> {
+        for name in names {
+            if let Some(pkg) = self.installed.get_mut(*name) {
+                pkg.dependency = dependency;
+            }
+        }
+        self.save()
+    }
+
+    /// Stop tracking `files` of installed package `name`, e.g. after
+    /// deleting them
+    pub fn forget_files(&mut self, name: &str, files: &[String]) -> Result<()> {
+        if let Some(pkg) = self.installed.get_mut(name) {
+            pkg.files.retain(|f| !files.contains(f));
+            pkg.file_hashes.retain(|f, _| !files.contains(f));
+        }
+        self.save()
+    }
+
     pub fn remove_installed(&mut self, name: &str) -> Result<()> {
         self.installed.remove(name);
         self.save()
     }
 }
 
-fn chrono_now() -> String {
-    // Simple timestamp without chrono dependency
-    let output = std::process::Command::new("date")
-        .arg("+%Y-%m-%d %H:%M:%S")
-        .output();
-    match output {
-        Ok(o) => String::from_utf8_lossy(&o.stdout).trim().to_string(),
-        Err(_) => "unknown".to_string(),
+/// Read just the installed package records, without the repo database
+pub fn load_installed(db_dir: &Path) -> Result<HashMap<String, InstalledPackage>> {
+    let path = db_dir.join("installed.json");
+    if !path.exists() {
+        return Ok(HashMap::new());
+    }
+    let content = fs::read_to_string(&path)?;
+    Ok(serde_json::from_str(&content)?)
+}
+
+/// Write a resolved plan as a list of exact name/version/checksum entries
+pub fn write_plan(plan: &[PackageInfo], requested: &[&str], path: &Path) -> Result<()> {
+    let entries: Vec<PlanEntry> = plan
+        .iter()
+        .map(|p| PlanEntry {
+            name: p.name.clone(),
+            version: p.version.clone(),
+            sha256: p.sha256.clone(),
+            dependency: !requested.contains(&p.name.as_str()),
+        })
+        .collect();
+    fs::write(path, serde_json::to_string_pretty(&entries)?)?;
+    Ok(())
+}
+
+/// Split a `depends` entry into its condition and the dependency itself:
+/// `"pulseaudio?audio-support"` needs audio-support only if pulseaudio is
+/// installed, while a plain `"zlib"` has no condition
+pub fn split_depend(entry: &str) -> (Option<&str>, &str) {
+    match entry.split_once('?') {
+        Some((cond, dep)) => (Some(cond), dep),
+        None => (None, entry),
+    }
+}
+
+/// Where a repo's synced database is stored
+pub fn repo_db_path(cfg: &Config, repo: &str) -> PathBuf {
+    cfg.db_dir.join("repos").join(format!("{}.json.zst", repo))
+}
+
+/// Read a repo's synced database. The "main" repo falls back to the
+/// `repo.json(.zst)` that older versions kept directly in db_dir. The JSON is
+/// parsed as it's decompressed, so the whole text is never held in memory.
+fn load_repo_db(cfg: &Config, repo: &str) -> Result<Vec<PackageInfo>> {
+    let repo_path = repo_db_path(cfg, repo);
+    let zst_path = if repo_path.exists() || repo != "main" {
+        repo_path
+    } else {
+        cfg.db_dir.join("repo.json.zst")
+    };
+    let plain_path = cfg.db_dir.join("repo.json");
+
+    if zst_path.exists() {
+        let decoder = zstd::Decoder::new(fs::File::open(&zst_path)?)?;
+        read_packages(BufReader::new(decoder))
+            .with_context(|| format!("Failed to read {}", zst_path.display()))
+    } else if repo == "main" && plain_path.exists() {
+        read_packages(BufReader::new(fs::File::open(&plain_path)?))
+            .with_context(|| format!("Failed to read {}", plain_path.display()))
+    } else {
+        Ok(Vec::new())
+    }
+}
+
+/// Parse a repo database, either a JSON array of packages or JSON Lines
+/// (`repo.jsonl`, one package per line), as it's read
+pub fn read_packages(mut reader: impl BufRead) -> Result<Vec<PackageInfo>> {
+    let array = loop {
+        let buf = reader.fill_buf()?;
+        match buf.iter().position(|b| !b.is_ascii_whitespace()) {
+            Some(start) => {
+                let array = buf[start] == b'[';
+                reader.consume(start);
+                break array;
+            }
+            None if buf.is_empty() => return Ok(Vec::new()),
+            None => {
+                let len = buf.len();
+                reader.consume(len);
+            }
+        }
+    };
+    if array {
+        return Ok(serde_json::from_reader(reader)?);
     }
+    serde_json::Deserializer::from_reader(reader)
+        .into_iter::<PackageInfo>()
+        .enumerate()
+        .map(|(i, pkg)| pkg.with_context(|| format!("Invalid package entry {}", i + 1)))
+        .collect()
 }
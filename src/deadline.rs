@@ -0,0 +1,206 @@
+use crate::error::Error;
+use anyhow::{bail, Result};
+use std::io::{Read, Write};
+use std::os::unix::process::CommandExt;
+use std::process::{Child, Command, ExitStatus, Output, Stdio};
+use std::sync::OnceLock;
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::time::{Duration, Instant};
+
+/// Set once from `--timeout`: when the command started, and how long it may run
+static DEADLINE: OnceLock<(Instant, Duration)> = OnceLock::new();
+
+pub fn set(limit: Duration) {
+    DEADLINE.set((Instant::now(), limit)).ok();
+}
+
+/// Time left before the deadline, or None without `--timeout`
+pub fn remaining() -> Option<Duration> {
+    let (start, limit) = DEADLINE.get()?;
+    Some(limit.saturating_sub(start.elapsed()))
+}
+
+pub fn expired() -> bool {
+    remaining().is_some_and(|left| left.is_zero())
+}
+
+/// The error a command stopped by `--timeout` fails with
+pub fn timeout_error() -> Error {
+    let limit = DEADLINE.get().map_or(0, |(_, limit)| limit.as_secs());
+    Error::Timeout(format!("Timed out after {}s (--timeout)", limit))
+}
+
+/// Report a failure that happened after the deadline passed, whatever gave
+/// way first (a cut-off download, a killed build), as the timeout
+pub fn attribute(err: anyhow::Error) -> anyhow::Error {
+    let timed_out = err
+        .chain()
+        .any(|cause| matches!(cause.downcast_ref::<Error>(), Some(Error::Timeout(_))));
+    if expired() && !timed_out {
+        err.context(timeout_error())
+    } else {
+        err
+    }
+}
+
+/// Fail once the deadline has passed. Called between phases, where stopping
+/// leaves nothing half done.
+pub fn check() -> Result<()> {
+    if expired() {
+        bail!(timeout_error());
+    }
+    Ok(())
+}
+
+/// Run `cmd` to completion, killing it and everything it started if the
+/// deadline passes first
+pub fn status(cmd: &mut Command) -> Result<ExitStatus> {
+    Ok(run(cmd, None)?.status)
+}
+
+/// `status`, with `input` written to the command's stdin
+pub fn status_with_input(cmd: &mut Command, input: &[u8]) -> Result<ExitStatus> {
+    Ok(run(cmd.stdin(Stdio::piped()), Some(input))?.status)
+}
+
+/// `Command::output`, killing the command if the deadline passes first
+pub fn output(cmd: &mut Command) -> Result<Output> {
+    run(cmd.stdout(Stdio::piped()).stderr(Stdio::piped()), None)
+}
+
+fn run(cmd: &mut Command, input: Option<&[u8]>) -> Result<Output> {
+    if DEADLINE.get().is_none() {
+        let mut child = cmd.spawn()?;
+        feed(&mut child, input);
+        return Ok(child.wait_with_output()?);
+    }
+    check()?;
+
+    // Its own process group, so a build's compilers die along with its
+    // shell. It takes the terminal from the child's side too, in case it
+    // reads from it before the parent gets there.
+    let tty = is_tty();
+    // SAFETY: setpgid, getpid, signal and tcsetpgrp are async-signal-safe
+    unsafe {
+        cmd.pre_exec(move || {
+            libc::setpgid(0, 0);
+            if tty {
+                give_terminal(libc::getpid());
+            }
+            Ok(())
+        });
+    }
+    let mut child = cmd.spawn()?;
+    let _group = Group::enter(child.id() as i32, tty);
+    feed(&mut child, input);
+    let stdout = child.stdout.take().map(read_in_background);
+    let stderr = child.stderr.take().map(read_in_background);
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+        if expired() {
+            // SAFETY: kill has no memory-safety preconditions; the group was
+            // created for this child above
+            unsafe { libc::kill(-(child.id() as i32), libc::SIGKILL) };
+            child.wait().ok();
+            bail!(timeout_error());
+        }
+        std::thread::sleep(Duration::from_millis(100));
+    };
+    let collect = |reader: Option<std::thread::JoinHandle<Vec<u8>>>| {
+        reader.map(|r| r.join().unwrap_or_default()).unwrap_or_default()
+    };
+    Ok(Output {
+        status,
+        stdout: collect(stdout),
+        stderr: collect(stderr),
+    })
+}
+
+/// Write `input` to the child's stdin and close it. A child that ignores its
+/// input may exit before reading it, so write errors are ignored.
+fn feed(child: &mut Child, input: Option<&[u8]>) {
+    if let (Some(mut stdin), Some(input)) = (child.stdin.take(), input) {
+        let _ = stdin.write_all(input);
+    }
+}
+
+/// Drain a child's output pipe on another thread, so a full pipe can't stall
+/// it while the deadline is being watched
+fn read_in_background(mut pipe: impl Read + Send + 'static) -> std::thread::JoinHandle<Vec<u8>> {
+    std::thread::spawn(move || {
+        let mut data = Vec::new();
+        pipe.read_to_end(&mut data).ok();
+        data
+    })
+}
+
+fn is_tty() -> bool {
+    // SAFETY: isatty only inspects the descriptor
+    unsafe { libc::isatty(libc::STDIN_FILENO) == 1 }
+}
+
+/// Make `group` the terminal's foreground process group, which is what
+/// Ctrl-C and terminal reads go to. Done from outside the foreground, so
+/// SIGTTOU, which would stop the caller, is ignored meanwhile.
+fn give_terminal(group: libc::pid_t) {
+    // SAFETY: plain syscalls on stdin; the SIGTTOU disposition is restored
+    unsafe {
+        let previous = libc::signal(libc::SIGTTOU, libc::SIG_IGN);
+        libc::tcsetpgrp(libc::STDIN_FILENO, group);
+        libc::signal(libc::SIGTTOU, previous);
+    }
+}
+
+/// Process group of the command `run` is waiting for, 0 when there is none
+static GROUP: AtomicI32 = AtomicI32::new(0);
+
+/// Signals passed on to the running command's group
+const FORWARDED: [libc::c_int; 3] = [libc::SIGINT, libc::SIGTERM, libc::SIGHUP];
+
+extern "C" fn forward(signal: libc::c_int) {
+    let group = GROUP.load(Ordering::SeqCst);
+    if group > 0 {
+        // SAFETY: kill is async-signal-safe
+        unsafe { libc::kill(-group, signal) };
+    }
+}
+
+/// A running command's process group: while it lives, the group has the
+/// terminal and gets the signals scarab receives, so Ctrl-C stops the
+/// command instead of leaving it behind. Dropping it gives both back.
+struct Group {
+    tty: bool,
+    handlers: Vec<libc::sighandler_t>,
+}
+
+impl Group {
+    fn enter(group: libc::pid_t, tty: bool) -> Self {
+        // Also from this side, in case the child hasn't got to it yet
+        // SAFETY: setpgid fails harmlessly once the child has exec'd
+        unsafe { libc::setpgid(group, group) };
+        if tty {
+            give_terminal(group);
+        }
+        GROUP.store(group, Ordering::SeqCst);
+        let handler = forward as extern "C" fn(libc::c_int) as libc::sighandler_t;
+        // SAFETY: `forward` only makes async-signal-safe calls
+        let handlers = FORWARDED.iter().map(|&s| unsafe { libc::signal(s, handler) }).collect();
+        Group { tty, handlers }
+    }
+}
+
+impl Drop for Group {
+    fn drop(&mut self) {
+        for (&signal, &handler) in FORWARDED.iter().zip(&self.handlers) {
+            // SAFETY: puts back the handler that was there before `enter`
+            unsafe { libc::signal(signal, handler) };
+        }
+        GROUP.store(0, Ordering::SeqCst);
+        if self.tty {
+            // SAFETY: getpgrp can't fail
+            give_terminal(unsafe { libc::getpgrp() });
+        }
+    }
+}
@@ -0,0 +1,248 @@
+use colored::Colorize;
+use std::fmt::Write;
+
+/// Lines of unchanged context around each change
+const CONTEXT: usize = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Op {
+    Same,
+    Removed,
+    Added,
+}
+
+/// Line edits turning `old` into `new`, from a shortest edit script found with
+/// Myers' linear-space algorithm. Each edit carries its line index in `old`
+/// and in `new`.
+fn edits(old: &[&str], new: &[&str]) -> Vec<(Op, usize, usize)> {
+    let mut edits = Vec::with_capacity(old.len().max(new.len()));
+    diff_range(old, new, (0, old.len()), (0, new.len()), &mut edits);
+    edits
+}
+
+/// Append the edits turning `old[a.0..a.1]` into `new[b.0..b.1]`: their
+/// common prefix and suffix are unchanged, and what's between is split where
+/// a shortest path through it crosses the middle, each half diffed in turn
+fn diff_range(
+    old: &[&str],
+    new: &[&str],
+    (mut a0, mut a1): (usize, usize),
+    (mut b0, mut b1): (usize, usize),
+    edits: &mut Vec<(Op, usize, usize)>,
+) {
+    while a0 < a1 && b0 < b1 && old[a0] == new[b0] {
+        edits.push((Op::Same, a0, b0));
+        a0 += 1;
+        b0 += 1;
+    }
+    let mut suffix = 0;
+    while a0 < a1 && b0 < b1 && old[a1 - 1] == new[b1 - 1] {
+        a1 -= 1;
+        b1 -= 1;
+        suffix += 1;
+    }
+
+    match middle(&old[a0..a1], &new[b0..b1]) {
+        Some((x, y)) => {
+            diff_range(old, new, (a0, a0 + x), (b0, b0 + y), edits);
+            diff_range(old, new, (a0 + x, a1), (b0 + y, b1), edits);
+        }
+        None => {
+            edits.extend((a0..a1).map(|i| (Op::Removed, i, b0)));
+            edits.extend((b0..b1).map(|j| (Op::Added, a1, j)));
+        }
+    }
+    edits.extend((0..suffix).map(|k| (Op::Same, a1 + k, b1 + k)));
+}
+
+/// Where a shortest path from the start of `old` and `new` to their ends can
+/// be split in two, found by searching forward from the start and backward
+/// from the end, one edit at a time, until the two searches overlap. None
+/// when either side is empty, leaving nothing to split.
+fn middle(old: &[&str], new: &[&str]) -> Option<(usize, usize)> {
+    let (n, m) = (old.len() as isize, new.len() as isize);
+    if n == 0 || m == 0 {
+        return None;
+    }
+    let max = (n + m + 1) / 2;
+    let delta = n - m;
+    // With an odd delta the searches can first meet after a forward step,
+    // otherwise after a backward one
+    let odd = delta % 2 != 0;
+
+    // The furthest x reached on each diagonal k = x - y, indexed by k + max.
+    // The backward search counts x and y from the ends.
+    let mut fwd = vec![-1isize; 2 * max as usize + 2];
+    let mut back = fwd.clone();
+    fwd[max as usize + 1] = 0;
+    back[max as usize + 1] = 0;
+    let furthest = |v: &[isize], d: isize, k: isize| {
+        let at = (k + max) as usize;
+        if k == -d || (k != d && v[at - 1] < v[at + 1]) {
+            v[at + 1]
+        } else {
+            v[at - 1] + 1
+        }
+    };
+    let seen = |v: &[isize], k: isize| {
+        let at = k + max;
+        (at >= 0 && (at as usize) < v.len() && v[at as usize] != -1).then(|| v[at as usize])
+    };
+
+    // Diagonals whose paths have left the grid, trimmed from either end
+    let (mut fwd_start, mut fwd_end, mut back_start, mut back_end) = (0, 0, 0, 0);
+    for d in 0..max {
+        for k in (-d + fwd_start..=d - fwd_end).step_by(2) {
+            let mut x = furthest(&fwd, d, k);
+            let mut y = x - k;
+            while x < n && y < m && old[x as usize] == new[y as usize] {
+                x += 1;
+                y += 1;
+            }
+            fwd[(k + max) as usize] = x;
+            if x > n {
+                fwd_end += 2;
+            } else if y > m {
+                fwd_start += 2;
+            } else if odd
+                && let Some(back_x) = seen(&back, delta - k)
+                && x >= n - back_x
+            {
+                return Some((x as usize, y as usize));
+            }
+        }
+        for k in (-d + back_start..=d - back_end).step_by(2) {
+            let mut x = furthest(&back, d, k);
+            let mut y = x - k;
+            while x < n && y < m && old[(n - x - 1) as usize] == new[(m - y - 1) as usize] {
+                x += 1;
+                y += 1;
+            }
+            back[(k + max) as usize] = x;
+            if x > n {
+                back_end += 2;
+            } else if y > m {
+                back_start += 2;
+            } else if !odd
+                && let Some(fwd_x) = seen(&fwd, delta - k)
+                && fwd_x >= n - x
+            {
+                let fwd_y = fwd_x - (delta - k);
+                return Some((fwd_x as usize, fwd_y as usize));
+            }
+        }
+    }
+    None
+}
+
+/// A unified diff of two texts, colored unless colors are turned off.
+/// Empty when they're the same.
+pub fn unified(old_label: &str, new_label: &str, old: &str, new: &str) -> String {
+    let old: Vec<_> = old.lines().collect();
+    let new: Vec<_> = new.lines().collect();
+    let edits = edits(&old, &new);
+
+    let changed: Vec<_> = (0..edits.len()).filter(|&k| edits[k].0 != Op::Same).collect();
+    if changed.is_empty() {
+        return String::new();
+    }
+
+    let mut out = String::new();
+    writeln!(out, "{}", format!("--- {}", old_label).bold()).unwrap();
+    writeln!(out, "{}", format!("+++ {}", new_label).bold()).unwrap();
+
+    // Group changes closer than twice the context into one hunk
+    let mut k = 0;
+    while k < changed.len() {
+        let start = changed[k].saturating_sub(CONTEXT);
+        let mut last = changed[k];
+        while k + 1 < changed.len() && changed[k + 1] - last <= 2 * CONTEXT {
+            k += 1;
+            last = changed[k];
+        }
+        let end = (last + CONTEXT + 1).min(edits.len());
+        k += 1;
+
+        let hunk = &edits[start..end];
+        let old_count = hunk.iter().filter(|e| e.0 != Op::Added).count();
+        let new_count = hunk.iter().filter(|e| e.0 != Op::Removed).count();
+        let (_, old_start, new_start) = hunk[0];
+        let header = format!(
+            "@@ -{},{} +{},{} @@",
+            old_start + usize::from(old_count > 0),
+            old_count,
+            new_start + usize::from(new_count > 0),
+            new_count
+        );
+        writeln!(out, "{}", header.cyan()).unwrap();
+        for &(op, i, j) in hunk {
+            let line = match op {
+                Op::Same => format!(" {}", old[i]).normal(),
+                Op::Removed => format!("-{}", old[i]).red(),
+                Op::Added => format!("+{}", new[j]).green(),
+            };
+            writeln!(out, "{}", line).unwrap();
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Op, edits, unified};
+
+    /// The edits as `-`, `+` and ` ` followed by the line
+    fn script(old: &[&str], new: &[&str]) -> Vec<String> {
+        edits(old, new)
+            .into_iter()
+            .map(|(op, i, j)| match op {
+                Op::Same => format!(" {}", old[i]),
+                Op::Removed => format!("-{}", old[i]),
+                Op::Added => format!("+{}", new[j]),
+            })
+            .collect()
+    }
+
+    fn plain_diff(old: &str, new: &str) -> String {
+        colored::control::set_override(false);
+        unified("a", "b", old, new)
+    }
+
+    #[test]
+    fn empty_sides() {
+        assert!(edits(&[], &[]).is_empty());
+        assert_eq!(edits(&[], &["a", "b"]), [(Op::Added, 0, 0), (Op::Added, 0, 1)]);
+        assert_eq!(edits(&["a", "b"], &[]), [(Op::Removed, 0, 0), (Op::Removed, 1, 0)]);
+        assert_eq!(plain_diff("", ""), "");
+    }
+
+    #[test]
+    fn pure_insert_and_delete() {
+        assert_eq!(script(&["a", "c"], &["a", "b", "c"]), [" a", "+b", " c"]);
+        assert_eq!(script(&["a", "b", "c"], &["a", "c"]), [" a", "-b", " c"]);
+        assert_eq!(script(&["a"], &["x", "y", "a"]), ["+x", "+y", " a"]);
+        assert_eq!(script(&["a", "x", "y"], &["a"]), [" a", "-x", "-y"]);
+    }
+
+    #[test]
+    fn interleaved_changes() {
+        let old = ["a", "b", "c", "d", "e", "f"];
+        let new = ["a", "x", "c", "d", "f", "g"];
+        assert_eq!(script(&old, &new), [" a", "-b", "+x", " c", " d", "-e", " f", "+g"]);
+        // Nothing in common
+        assert_eq!(script(&["a", "b"], &["c", "d"]), ["-a", "-b", "+c", "+d"]);
+    }
+
+    #[test]
+    fn hunk_headers() {
+        let old = "1\n2\n3\n4\n5\n6\n7\n8\n9\n10\n11\n12\n";
+        let new = "1\ntwo\n3\n4\n5\n6\n7\n8\n9\n10\n11\n";
+        assert_eq!(
+            plain_diff(old, new),
+            "--- a\n+++ b\n@@ -1,5 +1,5 @@\n 1\n-2\n+two\n 3\n 4\n 5\n\
+             @@ -9,4 +9,3 @@\n 9\n 10\n 11\n-12\n"
+        );
+        assert_eq!(plain_diff("", "a\nb\n"), "--- a\n+++ b\n@@ -0,0 +1,2 @@\n+a\n+b\n");
+        assert_eq!(plain_diff("a\nb\n", ""), "--- a\n+++ b\n@@ -1,2 +0,0 @@\n-a\n-b\n");
+    }
+}
@@ -0,0 +1,98 @@
+use crate::error::Error;
+use anyhow::{Context, Result, bail};
+use colored::Colorize;
+use std::ffi::CString;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+
+/// Parse a human-readable size like "10K", "1.5M" or "512" into bytes
+pub fn parse_size(s: &str) -> Option<u64> {
+    let s = s.trim();
+    let split = s
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(s.len());
+    let (num, unit) = s.split_at(split);
+    let num: f64 = num.parse().ok()?;
+    let scale: u64 = match unit.trim().trim_end_matches(['B', 'b', 'i']) {
+        "" => 1,
+        "K" | "k" => 1 << 10,
+        "M" | "m" => 1 << 20,
+        "G" | "g" => 1 << 30,
+        "T" | "t" => 1 << 40,
+        _ => return None,
+    };
+    Some((num * scale as f64) as u64)
+}
+
+pub fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "K", "M", "G", "T"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{}B", bytes)
+    } else {
+        format!("{:.1}{}", size, UNITS[unit])
+    }
+}
+
+/// A size change with an explicit sign, e.g. "+1.2M" or "-512B"
+pub fn format_delta(delta: i64) -> String {
+    let sign = if delta < 0 { '-' } else { '+' };
+    format!("{}{}", sign, format_size(delta.unsigned_abs()))
+}
+
+/// Bytes available to unprivileged users on the filesystem holding `path`.
+/// Missing directories are resolved to their nearest existing ancestor.
+pub fn available(path: &Path) -> Result<u64> {
+    let existing = path
+        .ancestors()
+        .find(|p| p.exists())
+        .unwrap_or(Path::new("/"));
+    let c_path = CString::new(existing.as_os_str().as_bytes())?;
+
+    // SAFETY: statvfs only writes into the struct we pass
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(c_path.as_ptr(), &mut stat) } != 0 {
+        return Err(std::io::Error::last_os_error())
+            .with_context(|| format!("Failed to stat filesystem of {}", existing.display()));
+    }
+    Ok(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+/// Whether the current user can write `path`, or create it when missing
+/// (judged by its nearest existing ancestor)
+pub fn writable(path: &Path) -> bool {
+    let existing = path
+        .ancestors()
+        .find(|p| p.exists())
+        .unwrap_or(Path::new("/"));
+    let Ok(c_path) = CString::new(existing.as_os_str().as_bytes()) else {
+        return false;
+    };
+    // SAFETY: access only reads the NUL-terminated path
+    unsafe { libc::access(c_path.as_ptr(), libc::W_OK) == 0 }
+}
+
+/// Fail if fewer than `needed` bytes are free under `path`, or only warn when
+/// `force` is set
+pub fn ensure_space(path: &Path, needed: u64, force: bool) -> Result<()> {
+    let have = available(path)?;
+    if needed <= have {
+        return Ok(());
+    }
+    let msg = format!(
+        "not enough space in {}: need {}, have {}",
+        path.display(),
+        format_size(needed),
+        format_size(have)
+    );
+    if !force {
+        bail!(Error::NoSpace(format!("{} (use -f to install anyway)", msg)));
+    }
+    eprintln!("{} {}", "warning:".yellow().bold(), msg);
+    Ok(())
+}
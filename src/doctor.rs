@@ -0,0 +1,169 @@
+use crate::config::Config;
+use crate::db::Database;
+use crate::{disk, fetch, verify};
+use std::time::Duration;
+
+/// Outcome of one health check
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    Pass,
+    Warn,
+    Fail,
+}
+
+pub struct Check {
+    pub name: &'static str,
+    pub status: Status,
+    /// What was found, or what's wrong
+    pub detail: String,
+}
+
+impl Check {
+    fn new(name: &'static str, status: Status, detail: impl Into<String>) -> Self {
+        Check {
+            name,
+            status,
+            detail: detail.into(),
+        }
+    }
+}
+
+/// Run every check, in order. Checks that need the database are reported
+/// as failed when it doesn't load.
+pub fn run(cfg: &Config) -> Vec<Check> {
+    let mut checks = vec![check_config(cfg), check_paths(cfg)];
+    match Database::load(cfg) {
+        Ok(db) => {
+            checks.push(Check::new(
+                "database",
+                Status::Pass,
+                format!("{} repo packages, {} installed", db.packages.len(), db.installed.len()),
+            ));
+            checks.push(check_files(cfg, &db));
+            checks.push(check_deps(&db));
+            checks.push(check_cache(cfg, &db));
+        }
+        Err(e) => checks.push(Check::new("database", Status::Fail, format!("{:#}", e))),
+    }
+    checks.push(check_sync(cfg));
+    checks
+}
+
+/// Settings that only fail once they're used
+fn check_config(cfg: &Config) -> Check {
+    let mut problems = Vec::new();
+    if !matches!(cfg.downloader.as_str(), "ureq" | "curl") {
+        problems.push(format!("unknown downloader '{}'", cfg.downloader));
+    }
+    if let Err(e) = fetch::download_limit(cfg) {
+        problems.push(e.to_string());
+    }
+    if !(1..=22).contains(&cfg.zstd_level) {
+        problems.push(format!("zstd_level {} is not within 1-22", cfg.zstd_level));
+    }
+    if problems.is_empty() {
+        Check::new("config", Status::Pass, "valid")
+    } else {
+        Check::new("config", Status::Fail, problems.join("; "))
+    }
+}
+
+/// Directories scarab writes to. Not being able to write them is only a
+/// warning, since read-only commands work without.
+fn check_paths(cfg: &Config) -> Check {
+    let unwritable: Vec<_> = cfg
+        .roots()
+        .into_iter()
+        .chain([cfg.db_dir.as_path(), cfg.cache_dir.as_path()])
+        .filter(|path| !disk::writable(path))
+        .map(|path| path.display().to_string())
+        .collect();
+    if unwritable.is_empty() {
+        Check::new("paths", Status::Pass, "root, db and cache are writable")
+    } else {
+        Check::new(
+            "paths",
+            Status::Warn,
+            format!("not writable (are you root?): {}", unwritable.join(", ")),
+        )
+    }
+}
+
+/// Every recorded file of every installed package is still there
+fn check_files(cfg: &Config, db: &Database) -> Check {
+    let mut broken = Vec::new();
+    let mut missing = 0;
+    for pkg in db.list_installed() {
+        let gone = pkg
+            .files
+            .iter()
+            .filter(|f| std::fs::symlink_metadata(pkg.root(cfg).join(f)).is_err())
+            .count();
+        if gone > 0 {
+            missing += gone;
+            broken.push(pkg.name.as_str());
+        }
+    }
+    if broken.is_empty() {
+        Check::new("files", Status::Pass, "all installed files present")
+    } else {
+        Check::new(
+            "files",
+            Status::Fail,
+            format!("{} files missing from: {} (see 'scarab verify')", missing, broken.join(", ")),
+        )
+    }
+}
+
+/// Installed packages don't depend on anything that isn't installed
+fn check_deps(db: &Database) -> Check {
+    let missing = db.missing_installed_deps();
+    if missing.is_empty() {
+        return Check::new("dependencies", Status::Pass, "all satisfied");
+    }
+    let list: Vec<_> = missing
+        .iter()
+        .map(|(pkg, dep)| format!("{} needs {}", pkg, dep))
+        .collect();
+    Check::new("dependencies", Status::Fail, list.join(", "))
+}
+
+/// Cached tarballs still match the checksums the repo lists for them
+fn check_cache(cfg: &Config, db: &Database) -> Check {
+    let files = match fetch::cached_files(cfg) {
+        Ok(files) => files,
+        Err(e) => return Check::new("cache", Status::Fail, format!("{:#}", e)),
+    };
+    let corrupt: Vec<_> = files
+        .iter()
+        .filter_map(|(filename, _)| {
+            let pkg = db.packages.iter().find(|p| p.filename == *filename)?;
+            let path = fetch::cache_path(cfg, pkg);
+            let hash = verify::sha256_file(&path).ok();
+            (!pkg.sha256.is_empty() && hash.as_deref() != Some(pkg.sha256.as_str()))
+                .then_some(filename.as_str())
+        })
+        .collect();
+    if corrupt.is_empty() {
+        Check::new("cache", Status::Pass, format!("{} files", files.len()))
+    } else {
+        Check::new(
+            "cache",
+            Status::Warn,
+            format!("checksum mismatch (delete to re-download): {}", corrupt.join(", ")),
+        )
+    }
+}
+
+/// The repo database was synced, and not too long ago
+fn check_sync(cfg: &Config) -> Check {
+    match fetch::last_sync_age(cfg) {
+        None => Check::new("sync", Status::Fail, "never synced (run 'scarab sync')"),
+        Some(age) => {
+            let days = age.as_secs() / 86400;
+            let stale = cfg.stale_days > 0 && age >= Duration::from_secs(cfg.stale_days * 86400);
+            let status = if stale { Status::Warn } else { Status::Pass };
+            Check::new("sync", status, format!("last synced {} days ago", days))
+        }
+    }
+}
@@ -0,0 +1,85 @@
+use std::fmt;
+use std::io;
+
+/// Failure classes that map to distinct exit codes. Modules raise these
+/// (wrapped in `anyhow::Error`) so `main` can tell them apart; any failure
+/// not raised as one of them exits with the generic code 1.
+#[derive(Debug)]
+pub enum Error {
+    /// A package, file or Portfile doesn't exist
+    NotFound(String),
+    /// A download or sync failed
+    Network(String),
+    /// A checksum or signature didn't match
+    Verification(String),
+    /// The operation wasn't permitted
+    Permission(String),
+    /// The request contradicts a pin, the target root or the package arch
+    Conflict(String),
+    /// A package archive couldn't be read or unpacked
+    Extraction(String),
+    /// The command ran past its `--timeout`
+    Timeout(String),
+    /// The target filesystem doesn't have room for the install
+    NoSpace(String),
+    /// A Portfile couldn't be read, or building or packaging it failed
+    Build(String),
+    /// The configuration is invalid
+    Config(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::NotFound(msg)
+            | Error::Network(msg)
+            | Error::Verification(msg)
+            | Error::Permission(msg)
+            | Error::Conflict(msg)
+            | Error::Extraction(msg)
+            | Error::Timeout(msg)
+            | Error::NoSpace(msg)
+            | Error::Build(msg)
+            | Error::Config(msg) => f.write_str(msg),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// Exit code for an error: 1 generic, 3 network, 4 verification,
+/// 5 permission denied, 6 conflict, 7 extraction, 8 timeout, 9 not found,
+/// 10 no space, 11 build, 12 config. 2 is left to clap, which exits with it
+/// on a usage error.
+pub fn exit_code(err: &anyhow::Error) -> u8 {
+    let code = |e: &Error| match e {
+        Error::Network(_) => 3,
+        Error::Verification(_) => 4,
+        Error::Permission(_) => 5,
+        Error::Conflict(_) => 6,
+        Error::Extraction(_) => 7,
+        Error::Timeout(_) => 8,
+        Error::NotFound(_) => 9,
+        Error::NoSpace(_) => 10,
+        Error::Build(_) => 11,
+        Error::Config(_) => 12,
+    };
+    // An Error attached as context doesn't show up in the chain as itself
+    if let Some(e) = err.downcast_ref::<Error>() {
+        return code(e);
+    }
+    for cause in err.chain() {
+        if let Some(e) = cause.downcast_ref::<Error>() {
+            return code(e);
+        }
+        if cause.downcast_ref::<ureq::Error>().is_some() {
+            return 3;
+        }
+        if let Some(e) = cause.downcast_ref::<io::Error>()
+            && e.kind() == io::ErrorKind::PermissionDenied
+        {
+            return 5;
+        }
+    }
+    1
+}
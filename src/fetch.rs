@@ -1,55 +1,519 @@
-use crate::config::Config;
+use crate::config::{Config, Repo};
+use crate::error::Error;
+use crate::keyring;
 use crate::package::PackageInfo;
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
 use std::fs;
-use std::io::Write;
-use std::path::PathBuf;
+use std::io::{BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-pub fn download_package(cfg: &Config, pkg: &PackageInfo) -> Result<PathBuf> {
-    let cache_dir = cfg.cache_dir.join("packages");
-    fs::create_dir_all(&cache_dir)?;
+/// Path a package's tarball is cached at
+pub fn cache_path(cfg: &Config, pkg: &PackageInfo) -> PathBuf {
+    cfg.cache_dir.join("packages").join(&pkg.filename)
+}
+
+/// Tarballs in the download cache with their sizes, sorted by filename
+pub fn cached_files(cfg: &Config) -> Result<Vec<(String, u64)>> {
+    let dir = cfg.cache_dir.join("packages");
+    if !dir.is_dir() {
+        return Ok(Vec::new());
+    }
+    let mut files = Vec::new();
+    for entry in fs::read_dir(&dir)? {
+        let entry = entry?;
+        let meta = entry.metadata()?;
+        if meta.is_file() {
+            files.push((entry.file_name().to_string_lossy().into_owned(), meta.len()));
+        }
+    }
+    files.sort();
+    Ok(files)
+}
 
-    let dest = cache_dir.join(&pkg.filename);
+pub fn download_package(cfg: &Config, pkg: &PackageInfo) -> Result<PathBuf> {
+    let dest = cache_path(cfg, pkg);
+    if let Some(cache_dir) = dest.parent() {
+        fs::create_dir_all(cache_dir)?;
+    }
 
     if dest.exists() {
         eprintln!("  -> Using cached {}", pkg.filename);
-        return Ok(dest);
+    } else {
+        let full_url = package_url(cfg, pkg);
+        eprintln!("  -> Downloading {}...", pkg.filename);
+        download_to(cfg, &full_url, &dest)?;
     }
 
-    let url = format!("v{}/{}", pkg.version, pkg.filename);
-    let full_url = format!("{}/{}", cfg.repo_url, url);
+    if let Some(sig) = sig_path(cfg, pkg)
+        && !sig.exists()
+    {
+        download_to(cfg, &sig_url(cfg, pkg), &sig)?;
+    }
+    Ok(dest)
+}
 
-    eprintln!("  -> Downloading {}...", pkg.filename);
+fn download_to(cfg: &Config, url: &str, dest: &Path) -> Result<()> {
+    if let Err(e) = fetch_to_file(cfg, url, dest) {
+        // Never leave a partial download in the cache
+        fs::remove_file(dest).ok();
+        bail!(Error::Network(format!("Failed to download {}: {:#}", url, e)));
+    }
+    Ok(())
+}
 
-    let resp = ureq::get(&full_url)
-        .call()
-        .with_context(|| format!("Failed to download {}", full_url))?;
+/// Path a package's signature is cached at, when the repo lists one
+pub fn sig_path(cfg: &Config, pkg: &PackageInfo) -> Option<PathBuf> {
+    let sig = pkg.sig_filename.as_ref()?;
+    Some(cfg.cache_dir.join("packages").join(sig))
+}
 
-    let mut file = fs::File::create(&dest)?;
-    let mut reader = resp.into_body().into_reader();
-    std::io::copy(&mut reader, &mut file)?;
-    file.flush()?;
+/// Where a package's signature is downloaded from: next to its tarball
+fn sig_url(cfg: &Config, pkg: &PackageInfo) -> String {
+    let sig = pkg.sig_filename.as_deref().unwrap_or_default();
+    let url = package_url(cfg, pkg);
+    match url.rsplit_once('/') {
+        Some((dir, _)) => format!("{}/{}", dir, sig),
+        None => sig.to_string(),
+    }
+}
 
-    Ok(dest)
+/// Where `pkg` is downloaded from: its own `url` if it has one, otherwise
+/// `{repo_url}/v{version}/{filename}` of the repo it was loaded from
+pub fn package_url(cfg: &Config, pkg: &PackageInfo) -> String {
+    if let Some(url) = &pkg.url {
+        return url.clone();
+    }
+    let repo_url = cfg
+        .repos()
+        .into_iter()
+        .find(|r| r.name == pkg.repo)
+        .map_or_else(|| cfg.repo_url.clone(), |r| r.url);
+    format!("{}/v{}/{}", repo_url, pkg.version, pkg.filename)
 }
 
+/// Download attempts per repo database when the network fails
+const SYNC_ATTEMPTS: u32 = 3;
+
+/// How syncing one repo went
+enum Synced {
+    Updated(usize),
+    Unchanged(usize),
+}
+
+/// Sync every repo concurrently, or one at a time under a `download_limit`
+/// (which each connection applies on its own). A repo that fails keeps its
+/// previous database and doesn't stop the others; the sync as a whole fails
+/// after reporting each repo.
 pub fn sync_repo_db(cfg: &Config) -> Result<()> {
-    let db_dir = &cfg.db_dir;
-    fs::create_dir_all(db_dir)?;
+    let repos = cfg.repos();
+    let results: Vec<Result<Synced>> = if !cfg.download_limit.is_empty() {
+        repos.iter().map(|repo| sync_repo(cfg, repo)).collect()
+    } else {
+        std::thread::scope(|scope| {
+            let handles: Vec<_> = repos
+                .iter()
+                .map(|repo| scope.spawn(move || sync_repo(cfg, repo)))
+                .collect();
+            handles
+                .into_iter()
+                .map(|h| h.join().unwrap_or_else(|_| Err(anyhow::anyhow!("sync thread panicked"))))
+                .collect()
+        })
+    };
+
+    let mut failed = 0;
+    for (repo, result) in repos.iter().zip(results) {
+        match result {
+            Ok(Synced::Updated(count)) => {
+                eprintln!("  -> {}: {} packages (updated)", repo.name, count)
+            }
+            Ok(Synced::Unchanged(count)) => {
+                eprintln!("  -> {}: {} packages (unchanged)", repo.name, count)
+            }
+            Err(e) => {
+                failed += 1;
+                eprintln!("  -> {}: failed: {:#}", repo.name, e);
+            }
+        }
+    }
+    if failed > 0 {
+        bail!(Error::Network(format!("{} of {} repos failed to sync", failed, repos.len())));
+    }
+
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    fs::write(cfg.db_dir.join("last_sync"), now.to_string())?;
+
+    Ok(())
+}
 
-    let url = format!("{}/latest/repo.json", cfg.repo_url);
-    eprintln!("  -> Fetching {}...", url);
+/// Fetch, check and store one repo's database, retrying network failures
+/// other than the database not being there
+fn sync_repo(cfg: &Config, repo: &Repo) -> Result<Synced> {
+    let mut attempt = 1;
+    let compressed = loop {
+        match fetch_repo_db(cfg, repo) {
+            Ok(compressed) => break compressed,
+            Err(e) if attempt < SYNC_ATTEMPTS && retryable(&e) => {
+                eprintln!("  -> {}: {:#}, retrying...", repo.name, e);
+                std::thread::sleep(Duration::from_secs(1 << (attempt - 1)));
+                attempt += 1;
+            }
+            Err(e) => return Err(e),
+        }
+    };
+    let count = parse_repo_db(repo, &compressed)?.len();
 
-    let resp = ureq::get(&url)
-        .call()
-        .with_context(|| format!("Failed to sync from {}", url))?;
+    let dest = crate::db::repo_db_path(cfg, &repo.name);
+    if fs::read(&dest).is_ok_and(|old| old == compressed) {
+        return Ok(Synced::Unchanged(count));
+    }
+    store_repo_db(cfg, repo, &compressed)?;
+    Ok(Synced::Updated(count))
+}
+
+/// Whether a failed fetch is worth retrying: a network error, but not a
+/// server (or local mirror) answering that the file doesn't exist
+fn retryable(e: &anyhow::Error) -> bool {
+    matches!(e.downcast_ref::<Error>(), Some(Error::Network(_))) && !is_missing(e)
+}
 
-    let body = resp.into_body().read_to_string()?;
-    fs::write(db_dir.join("repo.json"), &body)?;
+/// Whether a fetch failed because the file isn't there (404 or 410, or no
+/// such local file)
+fn is_missing(e: &anyhow::Error) -> bool {
+    e.chain().any(|cause| {
+        matches!(cause.downcast_ref::<ureq::Error>(), Some(ureq::Error::StatusCode(404 | 410)))
+            || cause
+                .downcast_ref::<std::io::Error>()
+                .is_some_and(|io| io.kind() == std::io::ErrorKind::NotFound)
+    })
+}
 
-    // Count packages
-    let packages: Vec<serde_json::Value> = serde_json::from_str(&body)?;
-    eprintln!("  -> {} packages in repository", packages.len());
+/// Database files a repo may serve under `latest/`, in order of preference:
+/// compressed before plain, and a JSON array before JSON Lines
+const REPO_DB_FILES: [&str; 4] = ["repo.json.zst", "repo.jsonl.zst", "repo.json", "repo.jsonl"];
 
+/// Download a repo's database, zstd-compressed whether or not the repo
+/// serves it that way. With keys in the keyring, it must come with a valid
+/// `.minisig` signature.
+fn fetch_repo_db(cfg: &Config, repo: &Repo) -> Result<Vec<u8>> {
+    let mut last_error = None;
+    for file in REPO_DB_FILES {
+        let url = format!("{}/latest/{}", repo.url, file);
+        if last_error.is_none() {
+            eprintln!("  -> Fetching {}...", url);
+        } else {
+            eprintln!("  -> Not available, fetching {}...", url);
+        }
+        let data = match fetch_bytes(cfg, &url) {
+            Ok(data) => data,
+            Err(e) => {
+                let failed = Error::Network(format!("Failed to sync from {}", url));
+                last_error = Some(e.context(failed));
+                continue;
+            }
+        };
+        verify_repo_signature(cfg, &url, &data)?;
+        if !file.ends_with(".zst") {
+            return Ok(zstd::encode_all(data.as_slice(), 0)?);
+        }
+        std::io::copy(&mut zstd::Decoder::new(data.as_slice())?, &mut std::io::sink())
+            .with_context(|| format!("Corrupt compressed database from {}", url))?;
+        return Ok(data);
+    }
+    Err(last_error.unwrap())
+}
+
+/// Require `<url>.minisig` to be a valid signature of `data` by a trusted
+/// key, if any keys are trusted at all
+fn verify_repo_signature(cfg: &Config, url: &str, data: &[u8]) -> Result<()> {
+    if keyring::list_keys(cfg)?.is_empty() {
+        return Ok(());
+    }
+    let sig_url = format!("{}.minisig", url);
+    // A missing signature fails verification; failing to fetch one is a
+    // network error like any other
+    let signature = fetch_bytes(cfg, &sig_url).map_err(|e| {
+        if is_missing(&e) {
+            Error::Verification(format!("No signature for {} ({:#})", url, e))
+        } else {
+            Error::Network(format!("Failed to fetch {}: {:#}", sig_url, e))
+        }
+    })?;
+    keyring::verify_signature(cfg, data, &String::from_utf8_lossy(&signature))
+        .with_context(|| format!("Bad signature for {}", url))
+}
+
+/// What probing a repo for its database found
+pub struct Probe {
+    /// The database URL that answered
+    pub url: String,
+    pub latency: Duration,
+    /// When the database last changed (`Last-Modified`, or the file's
+    /// mtime for a local repo), as seconds since the epoch
+    pub modified: Option<u64>,
+}
+
+/// Check that a repo serves its database, without downloading it: a HEAD
+/// request for each of the files `sync` would try, until one answers
+pub fn probe_repo(cfg: &Config, repo: &Repo) -> Result<Probe> {
+    let mut last_error = None;
+    for file in REPO_DB_FILES {
+        let url = format!("{}/latest/{}", repo.url, file);
+        let start = Instant::now();
+        match head(cfg, &url) {
+            Ok(modified) => {
+                return Ok(Probe {
+                    url,
+                    latency: start.elapsed(),
+                    modified,
+                });
+            }
+            Err(e) => last_error = Some(e),
+        }
+    }
+    Err(last_error.unwrap())
+}
+
+/// Ask for `url` without its body, returning its modification time if known
+fn head(cfg: &Config, url: &str) -> Result<Option<u64>> {
+    if let Some(path) = local_path(url) {
+        let meta = fs::metadata(&path).with_context(|| format!("{} not found", path.display()))?;
+        return Ok(meta.modified()?.duration_since(UNIX_EPOCH).ok().map(|d| d.as_secs()));
+    }
+    let last_modified = match cfg.downloader.as_str() {
+        "ureq" => {
+            let resp = with_deadline(ureq::head(url)).call()?;
+            resp.headers()
+                .get("last-modified")
+                .and_then(|v| v.to_str().ok())
+                .map(String::from)
+        }
+        "curl" => {
+            let output = curl(None).arg("-I").arg(url).output().context("Failed to run curl")?;
+            if !output.status.success() {
+                return Err(curl_error(&output));
+            }
+            String::from_utf8_lossy(&output.stdout).lines().find_map(|line| {
+                let (name, value) = line.split_once(':')?;
+                name.eq_ignore_ascii_case("last-modified").then(|| value.trim().to_string())
+            })
+        }
+        other => bail!(Error::Config(format!(
+            "Unknown downloader '{}' (expected ureq or curl)",
+            other
+        ))),
+    };
+    Ok(last_modified.and_then(|date| crate::timestamp::parse_http_date(&date)))
+}
+
+/// Every repo's current packages, fetched into memory without replacing
+/// the synced databases
+pub fn remote_packages(cfg: &Config) -> Result<Vec<PackageInfo>> {
+    let mut packages = Vec::new();
+    for repo in cfg.repos() {
+        packages.extend(parse_repo_db(&repo, &fetch_repo_db(cfg, &repo)?)?);
+    }
+    packages.retain(|p| p.matches_arch(&cfg.arch));
+    Ok(packages)
+}
+
+/// Decode a fetched repo database into its packages
+fn parse_repo_db(repo: &Repo, compressed: &[u8]) -> Result<Vec<PackageInfo>> {
+    let decoder = BufReader::new(zstd::Decoder::new(compressed)?);
+    let mut packages = crate::db::read_packages(decoder)
+        .with_context(|| format!("Invalid database from repo '{}'", repo.name))?;
+    for pkg in &mut packages {
+        pkg.repo = repo.name.clone();
+    }
+    Ok(packages)
+}
+
+/// Replace a repo's synced database through a temporary file and a rename,
+/// so it's never left half-written
+fn store_repo_db(cfg: &Config, repo: &Repo, compressed: &[u8]) -> Result<()> {
+    let dest = crate::db::repo_db_path(cfg, &repo.name);
+    if let Some(dir) = dest.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let tmp = dest.with_extension("zst.part");
+    fs::write(&tmp, compressed)
+        .and_then(|_| fs::rename(&tmp, &dest))
+        .inspect_err(|_| {
+            fs::remove_file(&tmp).ok();
+        })
+        .with_context(|| format!("Failed to write {}", dest.display()))?;
+
+    if repo.name == "main" {
+        // Drop the copies older versions kept directly in db_dir
+        fs::remove_file(cfg.db_dir.join("repo.json.zst")).ok();
+        fs::remove_file(cfg.db_dir.join("repo.json")).ok();
+    }
     Ok(())
 }
+
+/// Time since the last successful sync, if one was recorded
+pub fn last_sync_age(cfg: &Config) -> Option<Duration> {
+    let content = fs::read_to_string(cfg.db_dir.join("last_sync")).ok()?;
+    let synced = UNIX_EPOCH + Duration::from_secs(content.trim().parse().ok()?);
+    SystemTime::now().duration_since(synced).ok()
+}
+
+fn fetch_bytes(cfg: &Config, url: &str) -> Result<Vec<u8>> {
+    if let Some(path) = local_path(url) {
+        return fs::read(&path).with_context(|| format!("Failed to read {}", path.display()));
+    }
+    let rate = download_limit(cfg)?;
+    match cfg.downloader.as_str() {
+        "ureq" => {
+            let resp = with_deadline(ureq::get(url)).call()?;
+            // Repo databases can be tens of MB, well past ureq's default body
+            // limit, so read without one
+            let mut body = Vec::new();
+            Throttled::new(resp.into_body().into_reader(), rate).read_to_end(&mut body)?;
+            Ok(body)
+        }
+        "curl" => {
+            let output = curl(rate).arg(url).output().context("Failed to run curl")?;
+            if !output.status.success() {
+                return Err(curl_error(&output));
+            }
+            Ok(output.stdout)
+        }
+        other => bail!(Error::Config(format!(
+            "Unknown downloader '{}' (expected ureq or curl)",
+            other
+        ))),
+    }
+}
+
+fn fetch_to_file(cfg: &Config, url: &str, dest: &Path) -> Result<()> {
+    if let Some(path) = local_path(url) {
+        fs::copy(&path, dest).with_context(|| format!("Failed to copy {}", path.display()))?;
+        return Ok(());
+    }
+    let rate = download_limit(cfg)?;
+    match cfg.downloader.as_str() {
+        "ureq" => {
+            let resp = with_deadline(ureq::get(url)).call()?;
+            let mut file = fs::File::create(dest)?;
+            let mut reader = Throttled::new(resp.into_body().into_reader(), rate);
+            std::io::copy(&mut reader, &mut file)?;
+            file.flush()?;
+            Ok(())
+        }
+        "curl" => {
+            let output = curl(rate)
+                .arg("-o")
+                .arg(dest)
+                .arg(url)
+                .output()
+                .context("Failed to run curl")?;
+            if !output.status.success() {
+                return Err(curl_error(&output));
+            }
+            Ok(())
+        }
+        other => bail!(Error::Config(format!(
+            "Unknown downloader '{}' (expected ureq or curl)",
+            other
+        ))),
+    }
+}
+
+/// The on-disk path behind a `file://` URL or a plain path (any `repo_url`
+/// without a scheme), for mirrors on local disk, NFS or a mounted ISO
+fn local_path(url: &str) -> Option<PathBuf> {
+    if let Some(path) = url.strip_prefix("file://") {
+        Some(PathBuf::from(path))
+    } else if !url.contains("://") {
+        Some(PathBuf::from(url))
+    } else {
+        None
+    }
+}
+
+fn curl(rate: Option<u64>) -> Command {
+    let mut cmd = Command::new("curl");
+    cmd.arg("-fsSL");
+    if let Some(rate) = rate {
+        cmd.arg("--limit-rate").arg(rate.to_string());
+    }
+    if let Some(left) = crate::deadline::remaining() {
+        cmd.arg("--max-time").arg(format!("{:.1}", left.as_secs_f64().max(0.1)));
+    }
+    cmd
+}
+
+/// What a failed curl run reports, with the server answering 404 or 410
+/// kept as `NotFound` like a missing local file
+fn curl_error(output: &std::process::Output) -> anyhow::Error {
+    let message = String::from_utf8_lossy(&output.stderr).trim().to_string();
+    if message.contains("error: 404") || message.contains("error: 410") {
+        return std::io::Error::new(std::io::ErrorKind::NotFound, message).into();
+    }
+    anyhow::anyhow!(message)
+}
+
+/// Make a request give up, transfer included, when `--timeout` runs out
+fn with_deadline(
+    request: ureq::RequestBuilder<ureq::typestate::WithoutBody>,
+) -> ureq::RequestBuilder<ureq::typestate::WithoutBody> {
+    request.config().timeout_global(crate::deadline::remaining()).build()
+}
+
+/// `download_limit` in bytes per second, or None for unlimited
+pub fn download_limit(cfg: &Config) -> Result<Option<u64>> {
+    if cfg.download_limit.is_empty() {
+        return Ok(None);
+    }
+    match crate::disk::parse_size(&cfg.download_limit) {
+        Some(0) => Ok(None),
+        Some(rate) => Ok(Some(rate)),
+        None => bail!(Error::Config(format!(
+            "Invalid download limit '{}' (e.g. 500k, 2M)",
+            cfg.download_limit
+        ))),
+    }
+}
+
+/// A reader that sleeps as needed to average at most `rate` bytes per second
+struct Throttled<R> {
+    inner: R,
+    rate: Option<u64>,
+    start: Instant,
+    read: u64,
+}
+
+impl<R> Throttled<R> {
+    fn new(inner: R, rate: Option<u64>) -> Self {
+        Self {
+            inner,
+            rate,
+            start: Instant::now(),
+            read: 0,
+        }
+    }
+}
+
+impl<R: Read> Read for Throttled<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if crate::deadline::expired() {
+            return Err(std::io::Error::new(std::io::ErrorKind::TimedOut, "out of time"));
+        }
+        let Some(rate) = self.rate else {
+            return self.inner.read(buf);
+        };
+        // Read in tenth-of-a-second chunks so the pacing stays smooth
+        let chunk = buf.len().min((rate / 10).max(1) as usize);
+        let n = self.inner.read(&mut buf[..chunk])?;
+        self.read += n as u64;
+        let due = Duration::from_secs_f64(self.read as f64 / rate as f64);
+        if let Some(wait) = due.checked_sub(self.start.elapsed()) {
+            let left = crate::deadline::remaining().unwrap_or(Duration::MAX);
+            std::thread::sleep(wait.min(left));
+        }
+        Ok(n)
+    }
+}
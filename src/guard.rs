@@ -0,0 +1,81 @@
+use crate::config::Config;
+use crate::error::Error;
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+
+/// The root and arch a database manages, recorded on first use so later runs
+/// can't silently point it at a different system
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct Target {
+    root: PathBuf,
+    arch: String,
+}
+
+/// Refuse to change a system that doesn't match the database or the host:
+/// a foreign arch installed into `/`, or a db_dir that belongs to another root
+pub fn check(cfg: &Config) -> Result<()> {
+    let root = normalize(&cfg.root)?;
+
+    if root == Path::new("/") && cfg.arch != std::env::consts::ARCH {
+        bail!(Error::Conflict(format!(
+            "Refusing to install {} packages into the host root (this machine is {}); \
+             pass --root for a chroot or image",
+            cfg.arch,
+            std::env::consts::ARCH
+        )));
+    }
+
+    let marker = cfg.db_dir.join("target.json");
+    let current = Target {
+        root,
+        arch: cfg.arch.clone(),
+    };
+    if !marker.exists() {
+        fs::create_dir_all(&cfg.db_dir)?;
+        fs::write(&marker, serde_json::to_string_pretty(&current)?)?;
+        return Ok(());
+    }
+
+    let content = fs::read_to_string(&marker)?;
+    let mut recorded: Target =
+        serde_json::from_str(&content).with_context(|| format!("Invalid {}", marker.display()))?;
+    recorded.root = normalize(&recorded.root)?;
+    if recorded != current {
+        bail!(Error::Conflict(format!(
+            "Database {} manages root {} ({}), not {} ({}); \
+             check --root/--config, or delete {} if this is intended",
+            cfg.db_dir.display(),
+            recorded.root.display(),
+            recorded.arch,
+            current.root.display(),
+            current.arch,
+            marker.display()
+        )));
+    }
+    Ok(())
+}
+
+/// One form of `path` whether or not it exists yet: made absolute, with its
+/// deepest existing ancestor canonicalized and the rest appended, so
+/// `--root img` is the same root from any directory and once it's created
+fn normalize(path: &Path) -> Result<PathBuf> {
+    let path = std::path::absolute(path)
+        .with_context(|| format!("Invalid root {}", path.display()))?;
+    let (base, rest) = path
+        .ancestors()
+        .find_map(|dir| Some((fs::canonicalize(dir).ok()?, path.strip_prefix(dir).ok()?)))
+        .unwrap_or_else(|| (PathBuf::from("/"), path.as_path()));
+    let mut normalized = base;
+    for part in rest.components() {
+        match part {
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            Component::Normal(name) => normalized.push(name),
+            _ => {}
+        }
+    }
+    Ok(normalized)
+}
@@ -0,0 +1,90 @@
+use crate::config::Config;
+use crate::hooks::Change;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::PathBuf;
+
+/// One command that changed (or tried to change) the installed packages
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Transaction {
+    pub time: String,
+    /// The command line, without the program name
+    pub command: String,
+    /// Packages as "name old new", with "-" for none
+    #[serde(default)]
+    pub changes: Vec<String>,
+    /// Why the command failed, if it did
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub failure: Option<Failure>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Failure {
+    /// Install phase that failed (download, verify, ...), if known
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub phase: Option<String>,
+    pub reason: String,
+}
+
+/// Append-only log of transactions, one JSON object per line
+fn history_path(cfg: &Config) -> PathBuf {
+    cfg.db_dir.join("history.log")
+}
+
+/// Log a transaction: whatever changed, plus the failure if `error` is set.
+/// A command that neither changed nor failed isn't logged.
+pub fn record(
+    cfg: &Config,
+    changes: &[Change],
+    error: Option<&anyhow::Error>,
+    phase: Option<&str>,
+) -> Result<()> {
+    if changes.is_empty() && error.is_none() {
+        return Ok(());
+    }
+    let entry = Transaction {
+        time: crate::timestamp::now(),
+        command: std::env::args().skip(1).collect::<Vec<_>>().join(" "),
+        changes: changes
+            .iter()
+            .map(|c| {
+                format!(
+                    "{} {} {}",
+                    c.name,
+                    c.old.as_deref().unwrap_or("-"),
+                    c.new.as_deref().unwrap_or("-")
+                )
+            })
+            .collect(),
+        failure: error.map(|e| Failure {
+            phase: phase.map(String::from),
+            reason: format!("{:#}", e),
+        }),
+    };
+
+    let path = history_path(cfg);
+    fs::create_dir_all(&cfg.db_dir)?;
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)
+        .with_context(|| format!("Failed to open {}", path.display()))?;
+    writeln!(file, "{}", serde_json::to_string(&entry)?)?;
+    Ok(())
+}
+
+/// Every logged transaction, oldest first. Unreadable lines are skipped.
+pub fn load(cfg: &Config) -> Result<Vec<Transaction>> {
+    let path = history_path(cfg);
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+    let content =
+        fs::read_to_string(&path).with_context(|| format!("Failed to read {}", path.display()))?;
+    Ok(content
+        .lines()
+        .filter_map(|line| serde_json::from_str(line).ok())
+        .collect())
+}
@@ -0,0 +1,110 @@
+use crate::config::Config;
+use crate::db::InstalledPackage;
+use colored::Colorize;
+use std::collections::{BTreeSet, HashMap};
+use std::os::unix::fs::PermissionsExt;
+use std::path::PathBuf;
+use std::process::Command;
+
+/// A package whose installed version changed during a transaction
+pub struct Change {
+    pub name: String,
+    pub old: Option<String>,
+    pub new: Option<String>,
+}
+
+/// Compare installed package sets from before and after a transaction
+pub fn changes(
+    before: &HashMap<String, InstalledPackage>,
+    after: &HashMap<String, InstalledPackage>,
+) -> Vec<Change> {
+    let names: BTreeSet<_> = before.keys().chain(after.keys()).collect();
+    names
+        .into_iter()
+        .filter_map(|name| {
+            let old = before.get(name).map(|p| p.version.clone());
+            let new = after.get(name).map(|p| p.version.clone());
+            // A forced reinstall counts as a change too
+            let reinstalled = matches!(
+                (before.get(name), after.get(name)),
+                (Some(a), Some(b)) if a.installed_at != b.installed_at
+            );
+            (old != new || reinstalled).then(|| Change {
+                name: name.clone(),
+                old,
+                new,
+            })
+        })
+        .collect()
+}
+
+/// Run every executable in `hooks_dir`, in name order, once for the whole
+/// transaction. Hooks get the changed package names in `SCARAB_PACKAGES` and
+/// one "name old new" line per change (with "-" for none) on stdin. A failing
+/// hook only warns; the transaction is already done.
+pub fn run(cfg: &Config, changes: &[Change]) {
+    if changes.is_empty() {
+        return;
+    }
+    let hooks = match list_hooks(cfg) {
+        Ok(hooks) => hooks,
+        Err(e) => {
+            eprintln!(
+                "{} Cannot read {}: {}",
+                "warning:".yellow().bold(),
+                cfg.hooks_dir.display(),
+                e
+            );
+            return;
+        }
+    };
+    if hooks.is_empty() {
+        return;
+    }
+
+    let names: Vec<_> = changes.iter().map(|c| c.name.as_str()).collect();
+    let input: String = changes
+        .iter()
+        .map(|c| {
+            format!(
+                "{} {} {}\n",
+                c.name,
+                c.old.as_deref().unwrap_or("-"),
+                c.new.as_deref().unwrap_or("-")
+            )
+        })
+        .collect();
+
+    for hook in hooks {
+        let name = hook.file_name().unwrap_or_default().to_string_lossy().to_string();
+        eprintln!("  -> Running hook {}...", name);
+        let status = crate::deadline::status_with_input(
+            Command::new(&hook)
+                .env("SCARAB_ROOT", &cfg.root)
+                .env("SCARAB_PACKAGES", names.join(" ")),
+            input.as_bytes(),
+        );
+        match status {
+            Ok(status) if status.success() => {}
+            Ok(status) => eprintln!("{} Hook {} failed ({})", "warning:".yellow().bold(), name, status),
+            Err(e) => eprintln!("{} Hook {} failed: {:#}", "warning:".yellow().bold(), name, e),
+        }
+    }
+}
+
+/// Executable regular files in the hooks directory, sorted by name
+fn list_hooks(cfg: &Config) -> std::io::Result<Vec<PathBuf>> {
+    if !cfg.hooks_dir.is_dir() {
+        return Ok(Vec::new());
+    }
+    let mut hooks = Vec::new();
+    for entry in std::fs::read_dir(&cfg.hooks_dir)? {
+        let path = entry?.path();
+        let meta = std::fs::metadata(&path)?;
+        if meta.is_file() && meta.permissions().mode() & 0o111 != 0 {
+            hooks.push(path);
+        }
+    }
+    hooks.sort();
+    Ok(hooks)
+}
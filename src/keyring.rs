@@ -0,0 +1,164 @@
+use crate::config::Config;
+use crate::error::Error;
+use anyhow::{bail, Context, Result};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use ed25519_dalek::{Signature, Verifier, VerifyingKey};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// A trusted minisign (Ed25519) public key
+pub struct PublicKey {
+    pub id: [u8; 8],
+    pub key: VerifyingKey,
+    pub comment: String,
+}
+
+impl PublicKey {
+    /// Key id as minisign prints it
+    pub fn id_hex(&self) -> String {
+        format!("{:016X}", u64::from_le_bytes(self.id))
+    }
+
+    /// The key in minisign's one-line form (`minisign -P ...`)
+    pub fn encoded(&self) -> String {
+        let mut raw = b"Ed".to_vec();
+        raw.extend_from_slice(&self.id);
+        raw.extend_from_slice(self.key.as_bytes());
+        BASE64.encode(raw)
+    }
+}
+
+/// Parse a minisign public key file: an optional `untrusted comment:` line
+/// followed by base64("Ed" || key id || public key)
+pub fn parse_public_key(content: &str) -> Result<PublicKey> {
+    let mut comment = String::new();
+    let mut encoded = None;
+    for line in content.lines().map(str::trim).filter(|l| !l.is_empty()) {
+        if let Some(c) = line.strip_prefix("untrusted comment:") {
+            comment = c.trim().to_string();
+        } else {
+            encoded = Some(line);
+            break;
+        }
+    }
+
+    let raw = BASE64
+        .decode(encoded.context("Empty public key")?)
+        .context("Public key is not valid base64")?;
+    if raw.len() != 42 || &raw[..2] != b"Ed" {
+        bail!("Not a minisign Ed25519 public key");
+    }
+
+    let id = raw[2..10].try_into()?;
+    let key = VerifyingKey::from_bytes(raw[10..].try_into()?).context("Invalid Ed25519 public key")?;
+    Ok(PublicKey { id, key, comment })
+}
+
+pub fn keyring_dir(cfg: &Config) -> PathBuf {
+    cfg.db_dir.join("keyring")
+}
+
+/// Validate a public key file and copy it into the keyring
+pub fn add_key(cfg: &Config, path: &Path) -> Result<PublicKey> {
+    let content = fs::read_to_string(path)
+        .with_context(|| format!("Failed to read {}", path.display()))?;
+    let key = parse_public_key(&content)
+        .with_context(|| format!("Invalid public key {}", path.display()))?;
+
+    let dir = keyring_dir(cfg);
+    fs::create_dir_all(&dir)?;
+    fs::write(dir.join(format!("{}.pub", key.id_hex())), content)?;
+    Ok(key)
+}
+
+/// Every key in the keyring, sorted by id
+pub fn list_keys(cfg: &Config) -> Result<Vec<PublicKey>> {
+    let dir = keyring_dir(cfg);
+    if !dir.exists() {
+        return Ok(Vec::new());
+    }
+
+    let mut keys = Vec::new();
+    for entry in fs::read_dir(&dir)? {
+        let path = entry?.path();
+        if path.extension().is_some_and(|e| e == "pub") {
+            let content = fs::read_to_string(&path)?;
+            match parse_public_key(&content) {
+                Ok(key) => keys.push(key),
+                Err(e) => eprintln!("  -> Warning: ignoring {}: {}", path.display(), e),
+            }
+        }
+    }
+    keys.sort_by_key(|k| k.id_hex());
+    Ok(keys)
+}
+
+pub fn remove_key(cfg: &Config, id: &str) -> Result<()> {
+    let path = keyring_dir(cfg).join(format!("{}.pub", id.to_uppercase()));
+    if !path.exists() {
+        bail!(Error::NotFound(format!("No key {} in keyring", id)));
+    }
+    fs::remove_file(&path)?;
+    Ok(())
+}
+
+/// Check a minisign signature file for `data` against the keyring: the
+/// signature over the data itself and the global one over the trusted
+/// comment. Only the legacy `Ed` form (signing the raw data, `minisign -l`)
+/// is supported, since the prehashed form needs BLAKE2b.
+pub fn verify_signature(cfg: &Config, data: &[u8], signature: &str) -> Result<()> {
+    verify_with(&list_keys(cfg)?, data, signature)
+}
+
+/// Check a minisign signature file for `data`, trusting only `keys`. Any
+/// way the signature fails to check out, malformed or unsupported included,
+/// is a Verification error.
+pub fn verify_with(keys: &[PublicKey], data: &[u8], signature: &str) -> Result<()> {
+    let invalid = |msg: &str| Error::Verification(msg.to_string());
+    let mut lines = signature
+        .lines()
+        .map(str::trim)
+        .filter(|l| !l.is_empty() && !l.starts_with("untrusted comment:"));
+    let raw = BASE64
+        .decode(lines.next().ok_or_else(|| invalid("Empty signature"))?)
+        .map_err(|_| invalid("Signature is not valid base64"))?;
+    if raw.len() != 74 {
+        bail!(invalid("Not a minisign signature"));
+    }
+    match &raw[..2] {
+        b"Ed" => {}
+        b"ED" => bail!(invalid("Prehashed minisign signatures are not supported (sign with -l)")),
+        _ => bail!(invalid("Unknown signature algorithm")),
+    }
+
+    let id: [u8; 8] = raw[2..10].try_into()?;
+    let key = keys
+        .iter()
+        .find(|k| k.id == id)
+        .ok_or_else(|| {
+            Error::Verification(format!(
+                "Signed by untrusted key {:016X}",
+                u64::from_le_bytes(id)
+            ))
+        })?;
+    let sig = Signature::from_slice(&raw[10..]).map_err(|_| invalid("Malformed signature"))?;
+    key.key
+        .verify(data, &sig)
+        .map_err(|_| Error::Verification(format!("Invalid signature (key {})", key.id_hex())))?;
+
+    let comment = lines
+        .next()
+        .and_then(|l| l.strip_prefix("trusted comment: "))
+        .ok_or_else(|| invalid("Signature has no trusted comment"))?;
+    let global = BASE64
+        .decode(lines.next().ok_or_else(|| invalid("Signature has no global signature"))?)
+        .map_err(|_| invalid("Global signature is not valid base64"))?;
+    let global = Signature::from_slice(&global).map_err(|_| invalid("Malformed global signature"))?;
+    let mut signed = raw[10..].to_vec();
+    signed.extend_from_slice(comment.as_bytes());
+    key.key
+        .verify(&signed, &global)
+        .map_err(|_| Error::Verification("Invalid signature on the trusted comment".into()))?;
+    Ok(())
+}
@@ -1,18 +1,77 @@
 mod config;
+mod configs;
 mod db;
+mod deadline;
+mod diff;
+mod disk;
+mod doctor;
+mod error;
 mod fetch;
+mod guard;
+mod history;
+mod hooks;
+mod keyring;
 mod package;
+mod pager;
+mod ports;
+mod progress;
+mod rollback;
+mod store;
+mod timestamp;
+mod timing;
 mod verify;
+mod version;
 
-use anyhow::Result;
-use clap::{Parser, Subcommand};
+use anyhow::{Context, Result};
+use clap::{CommandFactory, Parser, Subcommand};
 use colored::Colorize;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt::Write;
+use std::process::ExitCode;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
 
 #[derive(Parser)]
 #[command(name = "scarab", version, about = "🪲 Scarab OS package manager")]
 struct Cli {
     #[command(subcommand)]
     command: Commands,
+    /// Use an alternate config file
+    #[arg(long, global = true, value_name = "PATH")]
+    config: Option<std::path::PathBuf>,
+    /// Use the paths of a profile defined in the config
+    #[arg(long, global = true, value_name = "NAME")]
+    profile: Option<String>,
+    /// Operate on packages for another architecture
+    #[arg(long, global = true)]
+    arch: Option<String>,
+    /// Install into another root, e.g. a chroot or image
+    #[arg(long, global = true, value_name = "PATH")]
+    root: Option<std::path::PathBuf>,
+    /// Use per-user (XDG) paths instead of the system ones
+    #[arg(long, global = true)]
+    user: bool,
+    /// Don't pipe long output through a pager
+    #[arg(long, global = true)]
+    no_pager: bool,
+    /// Report how long each install phase takes
+    #[arg(long, global = true)]
+    time: bool,
+    /// Don't show progress indicators
+    #[arg(short, long, global = true)]
+    quiet: bool,
+    /// Cap the download rate, e.g. 500k or 2M (bytes per second)
+    #[arg(long, global = true, value_name = "RATE", value_parser = parse_rate)]
+    limit_rate: Option<String>,
+    /// When to use colors
+    #[arg(long, global = true, value_enum, default_value = "auto")]
+    color: ColorChoice,
+    /// Flush installed files to disk before recording them (config `fsync`)
+    #[arg(long, global = true)]
+    fsync: bool,
+    /// Give up after this many seconds, between packages or phases (exit code 8)
+    #[arg(long, global = true, value_name = "SECS")]
+    timeout: Option<u64>,
 }
 
 #[derive(Subcommand)]
@@ -21,247 +80,3093 @@ enum Commands {
     Install {
         /// Package name(s)
         packages: Vec<String>,
-        /// Force reinstall
+        /// Force reinstall (also skips the free space check and installs
+        /// despite unresolvable dependencies)
         #[arg(short, long)]
         force: bool,
+        /// Install even if some dependencies can't be resolved (with a warning),
+        /// without forcing a reinstall
+        #[arg(long, conflicts_with = "plan")]
+        allow_unresolved: bool,
+        /// Sync the package database first
+        #[arg(short = 'y', long)]
+        sync: bool,
+        /// Treat a dependency as satisfied (PKG or PKG=VERSION, repeatable)
+        #[arg(long, value_name = "PKG")]
+        assume_installed: Vec<String>,
+        /// Write the resolved plan to a file instead of installing
+        #[arg(long, value_name = "FILE")]
+        export_plan: Option<std::path::PathBuf>,
+        /// Skip checksum verification (debugging only; never use on real systems)
+        #[arg(long)]
+        no_verify: bool,
+        /// Install the named packages from this repository
+        #[arg(long, value_name = "NAME")]
+        repo: Option<String>,
+        /// Install exactly the packages listed in an exported plan
+        #[arg(long, value_name = "FILE", conflicts_with_all = ["packages", "export_plan"])]
+        plan: Option<std::path::PathBuf>,
+        /// Print the download URLs of the resolved packages instead of installing
+        #[arg(long, conflicts_with_all = ["plan", "export_plan"])]
+        print_url: bool,
+        /// Don't ask which package to use for a virtual dependency; take the
+        /// one from the highest-priority repo
+        #[arg(long)]
+        yes: bool,
+        /// Extract up to N packages that don't depend on each other at once
+        /// (default: config `parallel_extract` and `jobs`; 1 = one at a time)
+        #[arg(short, long, value_name = "N")]
+        jobs: Option<usize>,
+        /// Also install the packages listed in FILE, one per line (`#` starts
+        /// a comment)
+        #[arg(long, value_name = "FILE", conflicts_with = "plan")]
+        from_file: Option<std::path::PathBuf>,
+        /// Also install the packages the installed ones recommend
+        #[arg(long, conflicts_with = "plan")]
+        install_recommends: bool,
+        /// Print why each package was selected or left out while resolving
+        #[arg(long, conflicts_with = "plan")]
+        explain: bool,
     },
     /// Remove a package
     Remove {
         /// Package name(s)
         packages: Vec<String>,
+        /// Only report what removing them would break or leave orphaned
+        #[arg(long)]
+        explain: bool,
+    },
+    /// Reinstall the installed version of packages
+    Reinstall {
+        /// Package name(s)
+        #[arg(required = true)]
+        packages: Vec<String>,
+        /// Also reinstall every installed dependency, dependencies first
+        #[arg(short = 'R', long)]
+        recursive: bool,
     },
     /// Search for packages
     Search {
         /// Search query
         query: String,
+        /// Pick results to install interactively
+        #[arg(short, long)]
+        install: bool,
+        /// Order results (default: repo order)
+        #[arg(long, value_enum)]
+        sort: Option<SearchSort>,
+        /// Reverse the result order
+        #[arg(long)]
+        reverse: bool,
+        /// Only search this repository
+        #[arg(long, value_name = "NAME")]
+        repo: Option<String>,
+        /// List every version a repo carries, not just the newest
+        #[arg(long)]
+        all_versions: bool,
     },
     /// List installed packages
-    List,
+    List {
+        /// Output template, e.g. "{name} {version} {size}"
+        /// (fields: name, version, date, size, files)
+        #[arg(long)]
+        format: Option<String>,
+        /// Tab-separated output for scripts
+        #[arg(long)]
+        no_align: bool,
+        /// Only show packages not found in the repo
+        #[arg(long)]
+        foreign: bool,
+        /// Only show packages installed within AGE, e.g. 7d, 12h or 2w (plain
+        /// numbers are days)
+        #[arg(long, visible_alias = "since", value_name = "AGE", value_parser = parse_age)]
+        newer_than: Option<u64>,
+        /// Only show packages installed longer than AGE ago
+        #[arg(long, value_name = "AGE", value_parser = parse_age)]
+        older_than: Option<u64>,
+        /// Order packages (default: name)
+        #[arg(long, value_enum)]
+        sort: Option<ListSort>,
+        /// Reverse the order
+        #[arg(long)]
+        reverse: bool,
+        /// List the repo packages in a group instead
+        #[arg(
+            long,
+            value_name = "NAME",
+            conflicts_with_all = ["format", "foreign", "newer_than", "older_than", "sort"]
+        )]
+        group: Option<String>,
+        /// List package tarballs in the download cache instead
+        #[arg(
+            long,
+            conflicts_with_all = ["format", "foreign", "newer_than", "older_than", "sort", "group"]
+        )]
+        cached: bool,
+    },
     /// Show package info
     Info {
-        /// Package name
-        package: String,
+        /// Package names
+        #[arg(required = true)]
+        packages: Vec<String>,
+        /// Also list installed files
+        #[arg(long)]
+        files: bool,
+        /// Also list every direct and indirect dependency with its status
+        #[arg(long)]
+        deps: bool,
+        /// Print the dependency tree instead
+        #[arg(long)]
+        tree: bool,
+        /// Limit the tree to this many levels
+        #[arg(long, value_name = "N", requires = "tree")]
+        depth: Option<usize>,
+        /// Look the package up in the live repo instead of the synced database
+        #[arg(long, conflicts_with = "tree")]
+        remote: bool,
+        /// Show the largest files of the installed package instead
+        #[arg(long, conflicts_with_all = ["files", "deps", "tree", "remote"])]
+        size_breakdown: bool,
+        /// Also list every version available in the repos
+        #[arg(long, conflicts_with_all = ["tree", "size_breakdown"])]
+        all_versions: bool,
+        /// How many files the size breakdown lists
+        #[arg(long, value_name = "N", default_value_t = 10, requires = "size_breakdown")]
+        top: usize,
+        /// Report packages that can't be shown and go on with the rest
+        #[arg(long)]
+        keep_going: bool,
     },
     /// Sync package database
     Sync,
+    /// Clone or update the ports tree from `ports_git`
+    PortsSync,
     /// Upgrade installed packages
-    Upgrade,
+    Upgrade {
+        /// Skip a package for this run (repeatable)
+        #[arg(long, value_name = "PKG")]
+        ignore: Vec<String>,
+        /// Don't ask before upgrading
+        #[arg(long)]
+        noconfirm: bool,
+        /// Review .scarabnew config files left by the upgrade afterwards
+        #[arg(long)]
+        ask: bool,
+        /// Also reinstall packages the repo rebuilt without a version bump
+        #[arg(long)]
+        reinstall_changed: bool,
+        /// Upgrade even if some dependencies can't be resolved (with a warning)
+        #[arg(short, long)]
+        force: bool,
+    },
+    /// Compare installed packages against the repo
+    Diff,
+    /// Check installed files against their recorded checksums
+    Verify {
+        /// Package name(s)
+        packages: Vec<String>,
+        /// Verify every installed package
+        #[arg(short, long)]
+        all: bool,
+        /// Number of hashing threads (default: config `jobs`)
+        #[arg(short, long)]
+        jobs: Option<usize>,
+        /// Reinstall packages with missing or modified files
+        #[arg(long)]
+        fix: bool,
+        /// Re-hash every file, even with `verify_cache` enabled
+        #[arg(long)]
+        full: bool,
+        /// Look for installed symlinks that point nowhere instead (with --fix,
+        /// remove them)
+        #[arg(long, conflicts_with = "full")]
+        dangling: bool,
+    },
+    /// Check the synced repo metadata for broken entries
+    VerifyRepo,
+    /// Review new versions of locally modified config files (.scarabnew)
+    Merge {
+        /// Show differences with the built-in colored diff instead of `diff -u`
+        #[arg(long)]
+        color_diff: bool,
+    },
     /// Build a package from Portfile
     Build {
         /// Package name
         package: String,
+        /// Compression for the built package
+        #[arg(long, value_enum, default_value = "zstd")]
+        compression: package::Compression,
+        /// Fetch the source and apply patches, but stop before build() and
+        /// report what the build would use
+        #[arg(long)]
+        dry_run: bool,
+        /// Directory for the built package (default: <cache_dir>/built)
+        #[arg(long, value_name = "PATH", conflicts_with = "dry_run")]
+        output_dir: Option<std::path::PathBuf>,
+        /// Print the source URL the build would fetch, with variables and
+        /// mirrors expanded, and exit
+        #[arg(long, conflicts_with_all = ["dry_run", "output_dir"])]
+        print_url: bool,
+    },
+    /// List a package tarball's contents and check them against its .PKGINFO
+    Inspect {
+        /// Package tarball
+        tarball: std::path::PathBuf,
+    },
+    /// Show past transactions
+    History {
+        /// Only show transactions that failed, with the reason
+        #[arg(long)]
+        failures: bool,
+        /// Show only the last N transactions
+        #[arg(short = 'n', long, value_name = "N")]
+        limit: Option<usize>,
+    },
+    /// Check config, database, installed files, cache and sync for problems
+    Doctor,
+    /// Check that each repo is reachable, how fast it answers and how
+    /// recently its database changed
+    MirrorStatus,
+    /// Empty the download cache, keeping tarballs saved for rollback
+    Clean {
+        /// Remove the rollback tarballs too
+        #[arg(long)]
+        rollback: bool,
+    },
+    /// Print the installed packages as a list for `install --from-file`
+    Export {
+        /// Include packages installed as dependencies, with exact versions
+        #[arg(long)]
+        all: bool,
+        /// Pin each package to its installed version (`name=version`)
+        #[arg(long)]
+        versions: bool,
+        /// Print a JSON array instead
+        #[arg(long)]
+        json: bool,
+    },
+    /// Manage trusted signing keys
+    Key {
+        #[command(subcommand)]
+        action: KeyAction,
+    },
+    /// Generate shell completion script
+    Completions {
+        /// Target shell
+        shell: clap_complete::Shell,
+    },
+}
+
+/// Choices for `--color`
+#[derive(Clone, Copy, clap::ValueEnum)]
+enum ColorChoice {
+    /// On a terminal, unless NO_COLOR is set
+    Auto,
+    Always,
+    Never,
+}
+
+/// Orderings for `search --sort`
+#[derive(Clone, Copy, clap::ValueEnum)]
+enum SearchSort {
+    Name,
+    Category,
+    Version,
+}
+
+/// Orderings for `list --sort`
+#[derive(Clone, Copy, clap::ValueEnum)]
+enum ListSort {
+    Name,
+    Version,
+    /// Install time, oldest first
+    Date,
+    /// Installed size, smallest first
+    Size,
+}
+
+#[derive(Subcommand)]
+enum KeyAction {
+    /// Add a minisign public key file to the keyring
+    Add {
+        /// Public key file
+        file: std::path::PathBuf,
+    },
+    /// List trusted keys
+    List,
+    /// Remove a key by id
+    Remove {
+        /// Key id as shown by `key list`
+        id: String,
     },
 }
 
-fn main() -> Result<()> {
-    let cli = Cli::parse();
-    let cfg = config::Config::load()?;
+impl Commands {
+    /// Whether the command installs or removes files under the root
+    fn modifies_root(&self) -> bool {
+        match self {
+            Commands::Install {
+                export_plan,
+                print_url,
+                ..
+            } => export_plan.is_none() && !print_url,
+            Commands::Remove { explain, .. } => !explain,
+            Commands::Reinstall { .. }
+            | Commands::Upgrade { .. }
+            | Commands::Merge { .. } => true,
+            Commands::Search { install, .. } => *install,
+            Commands::Verify { fix, .. } => *fix,
+            _ => false,
+        }
+    }
+}
+
+fn main() -> ExitCode {
+    match run() {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("Error: {:?}", e);
+            ExitCode::from(error::exit_code(&e))
+        }
+    }
+}
+
+fn run() -> Result<()> {
+    let start = std::time::Instant::now();
+    let cli = Cli::parse();
+    if let Some(secs) = cli.timeout {
+        deadline::set(std::time::Duration::from_secs(secs));
+    }
+    if cli.time {
+        timing::enable();
+    }
+    match cli.color {
+        ColorChoice::Auto => {}
+        ColorChoice::Always => colored::control::set_override(true),
+        ColorChoice::Never => colored::control::set_override(false),
+    }
+    if cli.quiet {
+        progress::set_quiet();
+    }
+
+    // Completions don't need a config (or a readable /etc)
+    if let Commands::Completions { shell } = cli.command {
+        clap_complete::generate(shell, &mut Cli::command(), "scarab", &mut std::io::stdout());
+        return Ok(());
+    }
+
+    let mut cfg = config::Config::load(cli.config.as_deref(), cli.user)?;
+    if let Some(profile) = &cli.profile {
+        cfg = cfg.with_profile(profile)?;
+    }
+    if let Some(arch) = cli.arch {
+        cfg.arch = arch;
+        check_arch(&cfg)?;
+    }
+    if let Some(root) = cli.root {
+        // An explicit root takes everything, whichever repo it comes from
+        cfg.root = root;
+        cfg.repos.iter_mut().for_each(|repo| repo.root = None);
+    }
+    if let Some(rate) = cli.limit_rate {
+        cfg.download_limit = rate;
+    }
+    cfg.fsync |= cli.fsync;
+    if cli.command.modifies_root() {
+        guard::check(&cfg)?;
+    }
+
+    // Hooks and the history log see whatever changed, even if the command
+    // failed partway
+    let modifies_root = cli.command.modifies_root();
+    let before = db::load_installed(&cfg.db_dir).unwrap_or_default();
+    let result = dispatch(&cfg, cli.command, !cli.no_pager).map_err(deadline::attribute);
+    let after = db::load_installed(&cfg.db_dir).unwrap_or_default();
+    let changes = hooks::changes(&before, &after);
+    if modifies_root
+        && let Err(e) = history::record(
+            &cfg,
+            &changes,
+            result.as_ref().err(),
+            timing::failed_phase(),
+        )
+    {
+        eprintln!("{} Cannot write history: {:#}", "warning:".yellow().bold(), e);
+    }
+    hooks::run(&cfg, &changes);
+
+    timing::total(start);
+    result
+}
+
+fn dispatch(cfg: &config::Config, command: Commands, pager: bool) -> Result<()> {
+    match command {
+        Commands::Install {
+            packages,
+            force,
+            allow_unresolved,
+            sync,
+            assume_installed,
+            export_plan: export_path,
+            no_verify,
+            repo,
+            plan,
+            print_url,
+            yes,
+            jobs,
+            from_file,
+            install_recommends,
+            explain,
+        } => {
+            let mut packages = packages;
+            if let Some(path) = &from_file {
+                packages.extend(read_package_list(path)?);
+            }
+            let opts = InstallOptions {
+                assume_installed,
+                no_verify,
+                repo,
+                yes,
+                extract_jobs: jobs,
+                recommends: install_recommends,
+                explain,
+                allow_unresolved: allow_unresolved || force,
+            };
+            if no_verify {
+                eprintln!(
+                    "{}",
+                    "WARNING: --no-verify is set, package checksums will NOT be checked!"
+                        .red()
+                        .bold()
+                );
+            }
+            if sync {
+                sync_db(cfg)?;
+            } else {
+                warn_if_stale(cfg);
+            }
+            if let Some(path) = plan {
+                install_from_plan(cfg, &path, force, &opts)?;
+            } else if let Some(path) = export_path {
+                export_plan(cfg, &packages, force, &opts, &path)?;
+            } else if print_url {
+                print_urls(cfg, &packages, force, &opts)?;
+            } else if from_file.is_some() {
+                let result = install_packages(cfg, &packages, force, &opts);
+                report_listed(cfg, &packages)?;
+                result?;
+            } else {
+                install_packages(cfg, &packages, force, &opts)?;
+            }
+        }
+        Commands::Remove { packages, explain } => {
+            if explain {
+                explain_removal(cfg, &packages)?;
+            } else {
+                for pkg in &packages {
+                    remove_package(cfg, pkg)?;
+                }
+            }
+        }
+        Commands::Reinstall {
+            packages,
+            recursive,
+        } => reinstall(cfg, &packages, recursive)?,
+        Commands::Search {
+            query,
+            install,
+            sort,
+            reverse,
+            repo,
+            all_versions,
+        } => {
+            let opts = SearchOptions {
+                sort,
+                reverse,
+                repo,
+                all_versions,
+            };
+            if install {
+                search_and_install(cfg, &query, &opts)?
+            } else {
+                search_packages(cfg, &query, &opts, pager)?
+            }
+        }
+        Commands::List {
+            format,
+            no_align,
+            foreign,
+            newer_than,
+            older_than,
+            sort,
+            reverse,
+            group,
+            cached,
+        } => match group {
+            Some(group) => list_group(cfg, &group, no_align, pager)?,
+            None if cached => list_cached(cfg, no_align, pager)?,
+            None => {
+                let opts = ListOptions {
+                    foreign,
+                    newer_than,
+                    older_than,
+                    sort,
+                    reverse,
+                };
+                list_packages(cfg, format.as_deref(), no_align, &opts, pager)?
+            }
+        },
+        Commands::Info {
+            packages,
+            files,
+            deps,
+            tree,
+            depth,
+            remote,
+            size_breakdown,
+            all_versions,
+            top,
+            keep_going,
+        } => for_each_package(&packages, keep_going, |package| {
+            if tree {
+                show_tree(cfg, package, depth)
+            } else if size_breakdown {
+                show_size_breakdown(cfg, package, top)
+            } else {
+                show_info(cfg, package, files, deps, remote, all_versions)
+            }
+        })?,
+        Commands::Sync => sync_db(cfg)?,
+        Commands::PortsSync => sync_ports(cfg)?,
+        Commands::Upgrade {
+            ignore,
+            noconfirm,
+            ask,
+            reinstall_changed,
+            force,
+        } => {
+            warn_if_stale(cfg);
+            upgrade_packages(cfg, &ignore, noconfirm, reinstall_changed, force)?;
+            if ask {
+                merge_configs(cfg, false)?
+            }
+        }
+        Commands::Diff => diff_packages(cfg, pager)?,
+        Commands::VerifyRepo => verify_repo(cfg)?,
+        Commands::Merge { color_diff } => merge_configs(cfg, color_diff)?,
+        Commands::Verify {
+            packages,
+            all,
+            jobs,
+            fix,
+            full,
+            dangling,
+        } => {
+            let jobs = jobs.unwrap_or_else(|| cfg.jobs());
+            if dangling {
+                verify_dangling(cfg, &packages, all, fix)?
+            } else {
+                verify_installed(cfg, &packages, all, jobs, fix, full)?
+            }
+        }
+        Commands::Build {
+            package,
+            compression,
+            dry_run,
+            output_dir,
+            print_url,
+        } => {
+            if print_url {
+                let portfile = package::find_portfile(&cfg.ports_dir, &package)?;
+                let port = package::read_portfile(&portfile, cfg)?;
+                if !port.source.is_empty() {
+                    println!("{}", port.source);
+                }
+            } else if dry_run {
+                dry_run_build(cfg, &package)?
+            } else {
+                let output_dir = output_dir.unwrap_or_else(|| cfg.cache_dir.join("built"));
+                build_package(cfg, &package, compression, &output_dir)?
+            }
+        }
+        Commands::History { failures, limit } => show_history(cfg, failures, limit, pager)?,
+        Commands::Doctor => run_doctor(cfg)?,
+        Commands::MirrorStatus => run_mirror_status(cfg)?,
+        Commands::Inspect { tarball } => inspect_package(cfg, &tarball, pager)?,
+        Commands::Clean { rollback } => clean_cache(cfg, rollback)?,
+        Commands::Export {
+            all,
+            versions,
+            json,
+        } => export_installed(cfg, all, versions || all, json)?,
+        Commands::Key { action } => manage_keys(cfg, action)?,
+        Commands::Completions { .. } => unreachable!(),
+    }
+
+    Ok(())
+}
+
+/// Make sure an overridden arch is one the synced repo actually carries
+fn check_arch(cfg: &config::Config) -> Result<()> {
+    let db = db::Database::load(cfg)?;
+    if !db.arches.is_empty() && !db.arches.contains(&cfg.arch) {
+        let available: Vec<_> = db.arches.iter().map(|a| a.as_str()).collect();
+        anyhow::bail!(error::Error::NotFound(format!(
+            "Repository has no packages for arch '{}' (available: {})",
+            cfg.arch,
+            available.join(", ")
+        )));
+    }
+    Ok(())
+}
+
+/// Settings for one install invocation, shared by every package it pulls in
+#[derive(Default)]
+struct InstallOptions {
+    /// Dependencies to treat as already satisfied
+    assume_installed: Vec<String>,
+    /// Skip checksum verification (`--no-verify`)
+    no_verify: bool,
+    /// Take the named packages from this repo (`--repo`)
+    repo: Option<String>,
+    /// Pick providers without asking (`--yes`)
+    yes: bool,
+    /// Concurrent extractions (`--jobs`), if not taken from the config
+    extract_jobs: Option<usize>,
+    /// Plan recommended packages too (`--install-recommends`)
+    recommends: bool,
+    /// Print the resolver's decisions (`--explain`)
+    explain: bool,
+    /// Only warn about dependencies nothing provides (`-f` or `--allow-unresolved`)
+    allow_unresolved: bool,
+}
+
+impl InstallOptions {
+    fn load_db(&self, cfg: &config::Config) -> Result<db::Database> {
+        let mut db = db::Database::load(cfg)?;
+        db.assume_installed(&self.assume_installed);
+        if self.recommends {
+            db.install_recommends();
+        }
+        if let Some(repo) = &self.repo {
+            db.prefer_repo(repo)?;
+        }
+        Ok(db)
+    }
+
+    /// How many packages to extract at once
+    fn extract_jobs(&self, cfg: &config::Config) -> usize {
+        self.extract_jobs
+            .unwrap_or_else(|| if cfg.parallel_extract { cfg.jobs() } else { 1 })
+    }
+}
+
+/// Install packages (or local package files) as one transaction: resolve a
+/// dependency-ordered plan up front, then install each package in turn
+fn install_packages(
+    cfg: &config::Config,
+    names: &[String],
+    force: bool,
+    opts: &InstallOptions,
+) -> Result<()> {
+    let (local, names): (Vec<_>, Vec<_>) = names.iter().partition(|name| {
+        let path = std::path::Path::new(name.as_str());
+        package::is_package_file(path) && path.is_file()
+    });
+    for path in local {
+        install_local_file(cfg, std::path::Path::new(path), force, opts)?;
+    }
+    if names.is_empty() {
+        return Ok(());
+    }
+
+    let mut db = opts.load_db(cfg)?;
+    let plan = resolve_plan(&mut db, &names, force, opts)?;
+    warn_partial_upgrade(&db, &plan);
+    for (rec, by) in db.skipped_recommends(&plan) {
+        eprintln!("  -> {} recommends {} (use --install-recommends)", by, rec);
+    }
+    // Remember which packages were asked for, for `export`
+    let requested: Vec<_> = names
+        .iter()
+        .flat_map(|name| match name.strip_prefix('@') {
+            Some(group) => db.group_members(group).iter().map(|p| p.name.as_str()).collect(),
+            None => vec![name.split_once('=').map_or(name.as_str(), |(name, _)| name)],
+        })
+        .collect();
+    let result = execute_plan(cfg, &plan, &requested, force, opts);
+
+    // Also those that were already installed as a dependency, without letting
+    // a failure here hide why the install failed
+    let marked = db::Database::load(cfg).and_then(|mut db| db.mark_dependency(&requested, false));
+    result.and(marked)
+}
+
+/// Package names from a list file: one per line, with blank lines and
+/// everything after a `#` ignored
+fn read_package_list(path: &std::path::Path) -> Result<Vec<String>> {
+    let content = std::fs::read_to_string(path).map_err(|e| {
+        error::Error::NotFound(format!("Cannot read package list {}: {}", path.display(), e))
+    })?;
+    Ok(content
+        .lines()
+        .map(|line| line.split('#').next().unwrap_or_default().trim())
+        .filter(|line| !line.is_empty())
+        .map(String::from)
+        .collect())
+}
+
+/// After installing a package list, say which of the listed packages ended
+/// up installed and which didn't
+fn report_listed(cfg: &config::Config, specs: &[String]) -> Result<()> {
+    let db = db::Database::load(cfg)?;
+    let names: Vec<_> = specs
+        .iter()
+        .filter(|spec| !spec.starts_with('@') && !package::is_package_file(spec.as_ref()))
+        .map(|spec| spec.split_once('=').map_or(spec.as_str(), |(name, _)| name))
+        .collect();
+    let missing: Vec<_> = names
+        .iter()
+        .filter(|name| db.get_installed(name).is_none())
+        .copied()
+        .collect();
+
+    println!(
+        "{} {} of {} listed packages installed",
+        "==>".green().bold(),
+        names.len() - missing.len(),
+        names.len()
+    );
+    if !missing.is_empty() {
+        eprintln!("{} Not installed: {}", "warning:".yellow().bold(), missing.join(", "));
+    }
+    Ok(())
+}
+
+/// Warn when new packages would be installed next to outdated versions of
+/// the dependencies they were built against
+fn warn_partial_upgrade(db: &db::Database, plan: &[package::PackageInfo]) {
+    let outdated = db.outdated_deps(plan);
+    if outdated.is_empty() {
+        return;
+    }
+    eprintln!(
+        "{} Partial upgrade: these installed dependencies are older than the repo:",
+        "warning:".yellow().bold()
+    );
+    for (name, installed, available) in &outdated {
+        eprintln!("    {} {} -> {}", name.bold(), installed, available);
+    }
+    eprintln!("  -> Run 'scarab upgrade' first if the new packages need the newer versions");
+}
+
+/// Resolve the packages to install for `names`, dependencies first. Already
+/// installed targets are skipped (and reported) unless forced. Virtual
+/// dependencies with several providers are asked about first, unless `--yes`.
+/// Dependencies nothing provides fail the resolution, unless `-f` or
+/// `--allow-unresolved`, in which case they're only warned about.
+fn resolve_plan(
+    db: &mut db::Database,
+    names: &[&String],
+    force: bool,
+    opts: &InstallOptions,
+) -> Result<Vec<package::PackageInfo>> {
+    let mut targets = Vec::new();
+    for name in names {
+        // @group installs whichever members are missing (or all, when forced)
+        if let Some(group) = name.strip_prefix('@') {
+            let members = db.group_members(group);
+            if members.is_empty() {
+                anyhow::bail!(error::Error::NotFound(format!("No packages in group '{}'", group)));
+            }
+            targets.extend(
+                members
+                    .into_iter()
+                    .filter(|p| force || db.get_installed(&p.name).is_none())
+                    .map(|p| p.name.clone()),
+            );
+            continue;
+        }
+        // name=version asks for that exact version, even over another installed one
+        let (name, version) = match name.split_once('=') {
+            Some((name, version)) => {
+                db.request_version(name, version)?;
+                (name, Some(version))
+            }
+            None => (name.as_str(), None),
+        };
+        if !force
+            && let Some(installed) = db.get_installed(name)
+            && version.is_none_or(|v| v == installed.version)
+        {
+            println!(
+                "{} {} {} is already installed (use -f to force)",
+                "==>".green().bold(),
+                name.bold(),
+                installed.version
+            );
+            continue;
+        }
+        targets.push(name.to_string());
+    }
+    let targets: Vec<_> = targets.iter().map(String::as_str).collect();
+    db.choose_providers(&targets, &mut |name, candidates| {
+        choose_provider(name, candidates, opts.yes)
+    })?;
+    let plan = {
+        let _spinner = progress::Spinner::start("Resolving dependencies...");
+        timing::phase("resolve", || db.install_plan(&targets))?
+    };
+    if opts.explain {
+        println!("{} Resolution:", "==>".green().bold());
+        for line in &plan.trace {
+            println!("  {}", line);
+        }
+    }
+    if !plan.unresolved.is_empty() {
+        let unresolved = describe_unresolved(&plan.unresolved);
+        if !opts.allow_unresolved {
+            anyhow::bail!(error::Error::NotFound(format!(
+                "Unresolvable dependencies: {} (use -f to go ahead anyway)",
+                unresolved
+            )));
+        }
+        eprintln!(
+            "{} Unresolvable dependencies: {}; installing anyway, the result may not work",
+            "warning:".yellow().bold(),
+            unresolved
+        );
+    }
+    Ok(plan.packages)
+}
+
+/// `x (needed by a, b), y (needed by c)`
+fn describe_unresolved(unresolved: &BTreeMap<String, Vec<String>>) -> String {
+    unresolved
+        .iter()
+        .map(|(dep, needed_by)| format!("{} (needed by {})", dep, needed_by.join(", ")))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Install a resolved plan. Packages named in `explicit` are recorded as
+/// asked for; the rest keep their install reason, or count as dependencies
+/// when new.
+fn execute_plan(
+    cfg: &config::Config,
+    plan: &[package::PackageInfo],
+    explicit: &[&str],
+    force: bool,
+    opts: &InstallOptions,
+) -> Result<()> {
+    check_space(cfg, plan, force)?;
+    if plan.len() > 1 {
+        let names: Vec<_> = plan
+            .iter()
+            .map(|p| format!("{}-{}", p.name, p.version))
+            .collect();
+        println!(
+            "{} Packages ({}): {}",
+            "==>".green().bold(),
+            plan.len(),
+            names.join(" ")
+        );
+    }
+    // Nothing is extracted until every tarball is in the cache and verified,
+    // so a failed download can't leave the dependency graph half installed
+    let fetched = if plan.iter().filter(|p| !p.is_meta()).count() > 1 {
+        fetch_plan(cfg, plan, opts)?
+    } else {
+        HashMap::new()
+    };
+    let jobs = opts.extract_jobs(cfg);
+    if jobs <= 1 {
+        for (i, pkg) in plan.iter().enumerate() {
+            let asked = explicit.contains(&pkg.name.as_str());
+            let tarball = fetched.get(&pkg.name).map(std::path::PathBuf::as_path);
+            install_resolved(cfg, pkg, (i + 1, plan.len()), asked, tarball, opts)?;
+        }
+        return Ok(());
+    }
+    let db = opts.load_db(cfg)?;
+    let mut done = 0;
+    for level in db.plan_levels(plan) {
+        let level: Vec<_> = level.into_iter().map(|i| &plan[i]).collect();
+        install_level(cfg, &level, (done, plan.len()), jobs, explicit, &fetched, opts)?;
+        done += level.len();
+    }
+    Ok(())
+}
+
+/// `[3/12] ` for the third package of a twelve-package transaction; nothing
+/// when there's only one
+fn step_prefix((position, total): (usize, usize)) -> String {
+    if total > 1 {
+        format!("[{}/{}] ", position, total)
+    } else {
+        String::new()
+    }
+}
+
+/// Install packages that don't depend on each other: download and verify
+/// each in turn (unless `fetched` already has it), extract up to `jobs` at
+/// once, then record them in plan order. Packages whose files overlap
+/// another's, or whose file list isn't known up front, are extracted one at a
+/// time afterwards instead. If any
+/// extraction fails, the others are still recorded before the error is
+/// returned. `(done, total)` counts the packages installed before this
+/// level out of the whole transaction.
+fn install_level(
+    cfg: &config::Config,
+    level: &[&package::PackageInfo],
+    (done, total): (usize, usize),
+    jobs: usize,
+    explicit: &[&str],
+    fetched: &HashMap<String, std::path::PathBuf>,
+    opts: &InstallOptions,
+) -> Result<()> {
+    let asked = |pkg: &package::PackageInfo| explicit.contains(&pkg.name.as_str());
+    if let [pkg] = level {
+        let tarball = fetched.get(&pkg.name).map(std::path::PathBuf::as_path);
+        return install_resolved(cfg, pkg, (done + 1, total), asked(pkg), tarball, opts);
+    }
+
+    let mut tarballs = Vec::new();
+    for (i, pkg) in level.iter().enumerate() {
+        println!(
+            "{} {}Installing {} {}...",
+            "==>".green().bold(),
+            step_prefix((done + i + 1, total)),
+            pkg.name.bold(),
+            pkg.version
+        );
+        // Meta-packages only exist to pull in their dependencies
+        tarballs.push(if pkg.is_meta() {
+            None
+        } else if let Some(tarball) = fetched.get(&pkg.name) {
+            Some(tarball.clone())
+        } else {
+            Some(fetch_verified(cfg, pkg, opts)?)
+        });
+    }
+
+    deadline::check()?;
+    let (parallel, serial) = split_overlapping(&tarballs);
+    let extracted: Vec<_> = level.iter().map(|_| Mutex::new(None)).collect();
+    let extract = |i: usize| {
+        if let Some(tarball) = &tarballs[i] {
+            let result = extract_keeping_configs(cfg, level[i], tarball);
+            *extracted[i].lock().unwrap() = Some(result);
+        }
+    };
+    if !parallel.is_empty() {
+        eprintln!("  -> Extracting {} packages, up to {} at once", parallel.len(), jobs);
+    }
+    let next = AtomicUsize::new(0);
+    std::thread::scope(|scope| {
+        for _ in 0..jobs.min(parallel.len()) {
+            scope.spawn(|| {
+                while let Some(&i) = parallel.get(next.fetch_add(1, Ordering::Relaxed)) {
+                    extract(i);
+                }
+            });
+        }
+    });
+    serial.into_iter().for_each(extract);
+
+    let mut first_error = None;
+    for (pkg, result) in level.iter().zip(extracted) {
+        let (files, kept) = match result.into_inner().unwrap() {
+            None => (Vec::new(), Vec::new()),
+            Some(Ok(extracted)) => extracted,
+            Some(Err(e)) => {
+                first_error.get_or_insert(e);
+                continue;
+            }
+        };
+        timing::phase("record", || record_files(cfg, pkg, files, kept, asked(pkg)))?;
+        println!(
+            "{} Installed {} {}",
+            "==>".green().bold(),
+            pkg.name.bold(),
+            pkg.version
+        );
+    }
+    first_error.map_or(Ok(()), Err)
+}
+
+/// Split the positions of `tarballs` into those safe to extract concurrently,
+/// whose `.PKGINFO` file lists are disjoint, and the rest
+fn split_overlapping(tarballs: &[Option<std::path::PathBuf>]) -> (Vec<usize>, Vec<usize>) {
+    let lists: Vec<_> = tarballs
+        .iter()
+        .map(|t| {
+            let tarball = t.as_ref()?;
+            package::read_pkginfo(tarball)
+                .ok()
+                .filter(|meta| !meta.files.is_empty())
+                .map(|meta| meta.files.into_keys().collect::<Vec<_>>())
+        })
+        .collect();
+    let mut owners: HashMap<&str, usize> = HashMap::new();
+    for files in lists.iter().flatten() {
+        for file in files {
+            *owners.entry(file).or_default() += 1;
+        }
+    }
+    (0..tarballs.len())
+        .filter(|&i| tarballs[i].is_some())
+        .partition(|&i| {
+            lists[i]
+                .as_ref()
+                .is_some_and(|files| files.iter().all(|f| owners[f.as_str()] == 1))
+        })
+}
+
+/// Make sure the cache can hold the downloads and each root the installed
+/// files of a plan before touching either
+fn check_space(cfg: &config::Config, plan: &[package::PackageInfo], force: bool) -> Result<()> {
+    let size = |p: &package::PackageInfo| disk::parse_size(&p.size).unwrap_or(0);
+    let download: u64 = plan
+        .iter()
+        .filter(|p| !p.is_meta() && !fetch::cache_path(cfg, p).exists())
+        .map(size)
+        .sum();
+    let mut install: BTreeMap<&std::path::Path, u64> = BTreeMap::new();
+    for pkg in plan {
+        *install.entry(cfg.root_for(&pkg.repo)).or_default() += size(pkg);
+    }
+
+    disk::ensure_space(&cfg.cache_dir, download, force)?;
+    for (root, needed) in install {
+        disk::ensure_space(root, needed, force)?;
+    }
+    Ok(())
+}
+
+/// Download, verify, extract and record one already-resolved package, the
+/// `step`th (position, total) of its transaction. `explicit` records it as
+/// asked for by name (see `Database::record_install`). A `fetched` tarball
+/// was already downloaded and verified with the rest of the plan.
+fn install_resolved(
+    cfg: &config::Config,
+    pkg: &package::PackageInfo,
+    step: (usize, usize),
+    explicit: bool,
+    fetched: Option<&std::path::Path>,
+    opts: &InstallOptions,
+) -> Result<()> {
+    deadline::check()?;
+    println!(
+        "{} {}Installing {} {}...",
+        "==>".green().bold(),
+        step_prefix(step),
+        pkg.name.bold(),
+        pkg.version
+    );
+
+    // Meta-packages only exist to pull in their dependencies
+    let (files, kept) = if pkg.is_meta() {
+        (Vec::new(), Vec::new())
+    } else {
+        // Download and verify
+        let tarball = match fetched {
+            Some(tarball) => tarball.to_path_buf(),
+            None => fetch_verified(cfg, pkg, opts)?,
+        };
+
+        // Extract to root; once started, the package is finished and recorded
+        deadline::check()?;
+        extract_keeping_configs(cfg, pkg, &tarball)?
+    };
+
+    // Record installation
+    timing::phase("record", || record_files(cfg, pkg, files, kept, explicit))?;
+
+    println!(
+        "{} Installed {} {}",
+        "==>".green().bold(),
+        pkg.name.bold(),
+        pkg.version
+    );
+    Ok(())
+}
+
+/// Extract a package tarball into the root for its repo, leaving the
+/// installed version's locally modified config files in place. Returns the
+/// extracted files and the config files that were kept.
+fn extract_keeping_configs(
+    cfg: &config::Config,
+    pkg: &package::PackageInfo,
+    tarball: &std::path::Path,
+) -> Result<(Vec<String>, Vec<configs::Kept>)> {
+    let root = cfg.root_for(&pkg.repo);
+    let installed = db::load_installed(&cfg.db_dir)?;
+    let old = installed.get(&pkg.name);
+    if let Some(old) = old
+        && old.version != pkg.version
+        && let Err(e) = rollback::retain(cfg, old)
+    {
+        eprintln!("{} {:#}", "warning:".yellow().bold(), e);
+    }
+    // Config files only carry over when the package stays in the same root
+    let moved = configs::set_aside(root, old.filter(|old| old.root(cfg) == root))?;
+    let result = timing::phase("extract", || package::extract_package(tarball, root));
+    let kept = configs::restore(root, moved)?;
+    let files = result?;
+
+    // A package now coming from a repo with another root leaves its old one
+    if let Some(old) = old
+        && old.root(cfg) != root
+    {
+        let db = db::Database::load(cfg)?;
+        let removal =
+            package::remove_package_files(cfg, old, &db.files_owned_by_others(cfg, old))?;
+        eprintln!("  -> Removed {} {} from {}", old.name, old.version, old.root(cfg).display());
+        report_removal(&removal);
+    }
+    Ok((files, kept))
+}
+
+/// Hash (and, with `dedup`, hardlink) the extracted files, then record the
+/// package as installed
+fn record_files(
+    cfg: &config::Config,
+    pkg: &package::PackageInfo,
+    files: Vec<String>,
+    kept: Vec<configs::Kept>,
+    explicit: bool,
+) -> Result<()> {
+    let _spinner = progress::Spinner::start("Recording files...");
+    let root = cfg.root_for(&pkg.repo);
+    let mut hashes = verify::hash_files(root, &files)?;
+    if cfg.dedup {
+        // Files extraction replaced may have been the last links to their
+        // entries; those go first, so the new files can take their place
+        store::prune(cfg)?;
+        let (linked, saved) = store::dedup_files(cfg, root, &hashes)?;
+        if linked > 0 {
+            eprintln!("  -> Hardlinked {} duplicate files ({} saved)", linked, disk::format_size(saved));
+        }
+    }
+    // Last, so the links dedup put in place are flushed too
+    if cfg.fsync {
+        timing::phase("fsync", || package::sync_files(root, &files))?;
+    }
+    // Kept config files record what the package shipped, so local edits
+    // still count as modified next time
+    for config in kept {
+        hashes.insert(config.path, config.shipped);
+    }
+    let mut db = db::Database::load(cfg)?;
+    db.record_install(pkg, files, hashes, explicit)
+}
+
+/// Write the resolved plan for `names` to `path` without installing anything
+fn export_plan(
+    cfg: &config::Config,
+    names: &[String],
+    force: bool,
+    opts: &InstallOptions,
+    path: &std::path::Path,
+) -> Result<()> {
+    let mut db = opts.load_db(cfg)?;
+    let names: Vec<_> = names.iter().collect();
+    let plan = resolve_plan(&mut db, &names, force, opts)?;
+    let requested: Vec<_> = names
+        .iter()
+        .map(|name| name.split_once('=').map_or(name.as_str(), |(name, _)| name))
+        .collect();
+    db::write_plan(&plan, &requested, path)?;
+    println!(
+        "{} Wrote plan with {} packages to {}",
+        "==>".green().bold(),
+        plan.len(),
+        path.display()
+    );
+    Ok(())
+}
+
+/// Print where each package of the resolved plan for `names` would be
+/// downloaded from, without fetching anything
+fn print_urls(
+    cfg: &config::Config,
+    names: &[String],
+    force: bool,
+    opts: &InstallOptions,
+) -> Result<()> {
+    let mut db = opts.load_db(cfg)?;
+    let names: Vec<_> = names.iter().collect();
+    let plan = resolve_plan(&mut db, &names, force, opts)?;
+    for pkg in plan.iter().filter(|p| !p.is_meta()) {
+        println!("{}", fetch::package_url(cfg, pkg));
+    }
+    Ok(())
+}
+
+/// Install exactly the packages of an exported plan, in its order
+fn install_from_plan(
+    cfg: &config::Config,
+    path: &std::path::Path,
+    force: bool,
+    opts: &InstallOptions,
+) -> Result<()> {
+    let db = db::Database::load(cfg)?;
+    let (plan, requested) = db.load_plan(path)?;
+
+    let plan: Vec<_> = plan
+        .into_iter()
+        .filter(|pkg| match db.get_installed(&pkg.name) {
+            Some(installed) if !force && installed.version == pkg.version => {
+                println!(
+                    "{} {} {} is already installed (use -f to force)",
+                    "==>".green().bold(),
+                    pkg.name.bold(),
+                    installed.version
+                );
+                false
+            }
+            _ => true,
+        })
+        .collect();
+    let requested: Vec<_> = requested.iter().map(String::as_str).collect();
+    execute_plan(cfg, &plan, &requested, force, opts)
+}
+
+/// Install a package tarball from disk using its embedded `.PKGINFO`,
+/// verified against a `<file>.sha256` sidecar when one exists
+fn install_local_file(
+    cfg: &config::Config,
+    path: &std::path::Path,
+    force: bool,
+    opts: &InstallOptions,
+) -> Result<()> {
+    let meta = package::read_pkginfo(path)?;
+    let db = opts.load_db(cfg)?;
+
+    if !meta.arch.is_empty() && meta.arch != "any" && meta.arch != cfg.arch {
+        anyhow::bail!(error::Error::Conflict(format!(
+            "{} is built for {}, not {} (use --arch to override)",
+            path.display(),
+            meta.arch,
+            cfg.arch
+        )));
+    }
+
+    if !force && let Some(installed) = db.get_installed(&meta.name) {
+        println!(
+            "{} {} {} is already installed (use -f to force)",
+            "==>".green().bold(),
+            meta.name.bold(),
+            installed.version
+        );
+        return Ok(());
+    }
+
+    println!(
+        "{} Installing {} {} from {}...",
+        "==>".green().bold(),
+        meta.name.bold(),
+        meta.version,
+        path.display()
+    );
+
+    let sidecar = std::path::PathBuf::from(format!("{}.sha256", path.display()));
+    let sha256 = match std::fs::read_to_string(&sidecar) {
+        Ok(content) => content.split_whitespace().next().unwrap_or("").to_string(),
+        Err(_) => String::new(),
+    };
+
+    let pkg = package::PackageInfo {
+        name: meta.name,
+        version: meta.version,
+        category: meta.category,
+        description: meta.description,
+        depends: meta.depends,
+        size: String::new(),
+        sha256,
+        filename: path
+            .file_name()
+            .map(|f| f.to_string_lossy().into_owned())
+            .unwrap_or_default(),
+        arch: meta.arch,
+        repo: String::new(),
+        groups: Vec::new(),
+        meta: false,
+        provides: Vec::new(),
+        recommends: Vec::new(),
+        url: None,
+        sig_filename: None,
+    };
+    if opts.no_verify {
+        warn_unverified(&pkg);
+    } else {
+        timing::phase("verify", || verify::verify_package(path, &pkg))?;
+    }
+
+    // Dependencies still come from the repo
+    let missing: Vec<_> = db
+        .active_depends(&pkg.depends)
+        .into_iter()
+        .filter(|dep| !db.is_satisfied(dep))
+        .collect();
+    if !missing.is_empty() {
+        println!("{} Dependencies: {}", "  ->".blue(), missing.join(", "));
+        let mut db = opts.load_db(cfg)?;
+        let missing: Vec<_> = missing.iter().collect();
+        let plan = resolve_plan(&mut db, &missing, false, opts)?;
+        execute_plan(cfg, &plan, &[], false, opts)?;
+    }
+
+    let (files, kept) = extract_keeping_configs(cfg, &pkg, path)?;
+
+    timing::phase("record", || record_files(cfg, &pkg, files, kept, true))?;
+
+    println!(
+        "{} Installed {} {}",
+        "==>".green().bold(),
+        pkg.name.bold(),
+        pkg.version
+    );
+    Ok(())
+}
+
+/// Download (or reuse the cached) tarball and verify its checksum and any
+/// signature. A cached file that fails verification is assumed corrupt and
+/// re-downloaded once; a bad file is never left behind in the cache.
+fn fetch_verified(
+    cfg: &config::Config,
+    pkg: &package::PackageInfo,
+    opts: &InstallOptions,
+) -> Result<std::path::PathBuf> {
+    deadline::check()?;
+    let was_cached = fetch::cache_path(cfg, pkg).exists();
+    let tarball = timing::phase("download", || fetch::download_package(cfg, pkg))?;
+    if opts.no_verify {
+        warn_unverified(pkg);
+        return Ok(tarball);
+    }
+
+    // The checksum first: a signature over a corrupt download can't match
+    let check = |tarball: &std::path::Path| {
+        timing::phase("verify", || {
+            verify::verify_package(tarball, pkg)?;
+            verify::verify_package_signature(cfg, tarball, pkg)
+        })
+    };
+    let discard = |tarball: &std::path::Path| {
+        std::fs::remove_file(tarball).ok();
+        if let Some(sig) = fetch::sig_path(cfg, pkg) {
+            std::fs::remove_file(sig).ok();
+        }
+    };
+
+    match check(&tarball) {
+        Ok(()) => return Ok(tarball),
+        Err(e) => {
+            discard(&tarball);
+            if !was_cached {
+                return Err(e);
+            }
+            eprintln!("  -> {}", e);
+            eprintln!("  -> Cached {} is corrupt, re-downloading...", pkg.filename);
+        }
+    }
+
+    let tarball = timing::phase("download", || fetch::download_package(cfg, pkg))?;
+    if let Err(e) = check(&tarball) {
+        discard(&tarball);
+        return Err(e);
+    }
+    Ok(tarball)
+}
+
+fn warn_unverified(pkg: &package::PackageInfo) {
+    eprintln!(
+        "  {} {}",
+        "->".red().bold(),
+        format!("NOT verifying {} {} (--no-verify)", pkg.name, pkg.version).red()
+    );
+}
+
+fn remove_package(cfg: &config::Config, name: &str) -> Result<()> {
+    deadline::check()?;
+    let mut db = db::Database::load(cfg)?;
+
+    let installed = db
+        .get_installed(name)
+        .ok_or_else(|| error::Error::NotFound(format!("{} is not installed", name)))?
+        .clone();
+
+    println!(
+        "{} Removing {} {}...",
+        "==>".green().bold(),
+        name.bold(),
+        installed.version
+    );
+
+    // Remove files, keeping anything shared with other packages
+    let shared = db.files_owned_by_others(cfg, &installed);
+    let removal = package::remove_package_files(cfg, &installed, &shared)?;
+
+    // Remove from db
+    db.remove_installed(name)?;
+    if cfg.dedup {
+        store::prune(cfg)?;
+    }
+
+    report_removal(&removal);
+    println!("{} Removed {}", "==>".green().bold(), name.bold());
+    Ok(())
+}
+
+/// Summarize a package's file removal, naming anything left behind and any
+/// recorded file that was already gone
+fn report_removal(removal: &package::Removal) {
+    for (path, e) in &removal.failed {
+        eprintln!("{} could not remove {}: {}", "warning:".yellow().bold(), path.display(), e);
+    }
+    for path in &removal.missing {
+        eprintln!("  -> Already missing: {}", path.display());
+    }
+    eprintln!(
+        "  -> Removed {} files, failed {}, already missing {}",
+        removal.removed,
+        removal.failed.len(),
+        removal.missing.len()
+    );
+}
+
+/// Report what removing `names` would do without removing anything: the
+/// packages themselves, installed packages that would lose a dependency,
+/// and dependencies that would be left orphaned
+fn explain_removal(cfg: &config::Config, names: &[String]) -> Result<()> {
+    let db = db::Database::load(cfg)?;
+    for name in names {
+        let pkg = db
+            .get_installed(name)
+            .ok_or_else(|| error::Error::NotFound(format!("{} is not installed", name)))?;
+        println!(
+            "{} Removing {} {} deletes {} files",
+            "==>".green().bold(),
+            name.bold(),
+            pkg.version,
+            pkg.file_entries().count()
+        );
+    }
+
+    let impact = db.removal_impact(names);
+    let version = |name: &str| db.get_installed(name).map_or("", |p| p.version.as_str());
+    println!("{} Would break ({}):", "==>".green().bold(), impact.dependents.len());
+    for (name, needs) in &impact.dependents {
+        println!("  {:<20} {}  (needs {})", name.bold(), version(name), needs);
+    }
+    println!("{} Would be orphaned ({}):", "==>".green().bold(), impact.orphans.len());
+    for name in &impact.orphans {
+        println!("  {:<20} {}", name.bold(), version(name));
+    }
+    Ok(())
+}
+
+/// How to filter and order search results
+struct SearchOptions {
+    sort: Option<SearchSort>,
+    reverse: bool,
+    repo: Option<String>,
+    all_versions: bool,
+}
+
+/// Search results, filtered and ordered as requested
+fn sorted_search<'a>(
+    db: &'a db::Database,
+    query: &str,
+    opts: &SearchOptions,
+) -> Vec<&'a package::PackageInfo> {
+    let mut results = db.search(query);
+    if let Some(repo) = &opts.repo {
+        results.retain(|p| p.repo == *repo);
+    }
+    if !opts.all_versions {
+        results.retain(|p| db.newest_in(&p.repo, &p.name).is_some_and(|n| std::ptr::eq(n, *p)));
+    }
+    match opts.sort {
+        Some(SearchSort::Name) => results.sort_by(|a, b| a.name.cmp(&b.name)),
+        Some(SearchSort::Category) => {
+            results.sort_by(|a, b| a.category.cmp(&b.category).then_with(|| a.name.cmp(&b.name)))
+        }
+        Some(SearchSort::Version) => results.sort_by(|a, b| {
+            version::compare(&a.version, &b.version).then_with(|| a.name.cmp(&b.name))
+        }),
+        None => {}
+    }
+    if opts.reverse {
+        results.reverse();
+    }
+    results
+}
+
+fn search_packages(
+    cfg: &config::Config,
+    query: &str,
+    opts: &SearchOptions,
+    pager: bool,
+) -> Result<()> {
+    let db = db::Database::load(cfg)?;
+    let results = sorted_search(&db, query, opts);
+
+    if results.is_empty() {
+        println!("No packages found for '{}'", query);
+        return Ok(());
+    }
+
+    let mut out = String::new();
+    for pkg in results {
+        let (status, note) = match db.get_installed(&pkg.name) {
+            Some(installed) if installed.version != pkg.version => (
+                "*".green().to_string(),
+                format!(" [installed: {}]", installed.version).yellow().to_string(),
+            ),
+            Some(_) => ("*".green().to_string(), String::new()),
+            None => (" ".to_string(), String::new()),
+        };
+        writeln!(
+            out,
+            "{} {}/{} {}{} - {}",
+            status,
+            pkg.category.dimmed(),
+            pkg.name.bold(),
+            pkg.version,
+            note,
+            pkg.description
+        )?;
+    }
+    pager::page(&out, pager)
+}
+
+fn search_and_install(cfg: &config::Config, query: &str, opts: &SearchOptions) -> Result<()> {
+    let db = db::Database::load(cfg)?;
+    let results = sorted_search(&db, query, opts);
+
+    if results.is_empty() {
+        println!("No packages found for '{}'", query);
+        return Ok(());
+    }
+
+    for (i, pkg) in results.iter().enumerate() {
+        let status = match db.get_installed(&pkg.name) {
+            Some(installed) if installed.version != pkg.version => {
+                format!(" [installed: {}]", installed.version).yellow().to_string()
+            }
+            Some(_) => " [installed]".green().to_string(),
+            None => String::new(),
+        };
+        println!(
+            "{:>3} {}/{} {}{} - {}",
+            (i + 1).to_string().bold(),
+            pkg.category.dimmed(),
+            pkg.name.bold(),
+            pkg.version,
+            status,
+            pkg.description
+        );
+    }
+
+    let answer = prompt("==> Packages to install (e.g. 1 2 4-6): ")?.unwrap_or_default();
+    let selection = parse_selection(&answer, results.len())?;
+    if selection.is_empty() {
+        println!("Nothing selected");
+        return Ok(());
+    }
+
+    // A listed older version is installed as exactly that version
+    let names: Vec<String> = selection
+        .iter()
+        .map(|&i| {
+            if opts.all_versions {
+                format!("{}={}", results[i].name, results[i].version)
+            } else {
+                results[i].name.clone()
+            }
+        })
+        .collect();
+    let install = InstallOptions {
+        repo: opts.repo.clone(),
+        ..InstallOptions::default()
+    };
+    install_packages(cfg, &names, false, &install)
+}
+
+/// Ask which of several packages should provide virtual dependency `name`.
+/// The first candidate, from the highest-priority repo, is the default and is
+/// taken without asking when `yes` is set.
+fn choose_provider(name: &str, candidates: &[&package::PackageInfo], yes: bool) -> Result<usize> {
+    if yes {
+        eprintln!("  -> Using {} to provide {}", candidates[0].name, name);
+        return Ok(0);
+    }
+
+    println!(
+        "{} {} packages provide {}:",
+        "==>".green().bold(),
+        candidates.len(),
+        name.bold()
+    );
+    for (i, pkg) in candidates.iter().enumerate() {
+        println!(
+            "  {:>3}) {} {} {}",
+            i + 1,
+            pkg.name.bold(),
+            pkg.version,
+            format!("[{}]", pkg.repo).dimmed()
+        );
+    }
+    loop {
+        let Some(answer) = prompt("==> Choose a provider [1]: ")? else {
+            anyhow::bail!("No provider chosen for {} (use --yes to take the first)", name);
+        };
+        if answer.is_empty() {
+            return Ok(0);
+        }
+        match answer.parse::<usize>() {
+            Ok(n) if (1..=candidates.len()).contains(&n) => return Ok(n - 1),
+            _ => println!("Enter a number from 1 to {}", candidates.len()),
+        }
+    }
+}
+
+/// Print a prompt and read one line from stdin, or None if stdin is at end
+/// of file, so a closed or empty stdin is never taken as the default answer
+fn prompt(message: &str) -> Result<Option<String>> {
+    use std::io::Write as _;
+
+    print!("{}", message);
+    std::io::stdout().flush()?;
+    let mut line = String::new();
+    if std::io::stdin().read_line(&mut line)? == 0 {
+        println!();
+        return Ok(None);
+    }
+    Ok(Some(line.trim().to_string()))
+}
+
+/// Parse a selection like "1 3 5-7" into zero-based indices
+fn parse_selection(input: &str, count: usize) -> Result<Vec<usize>> {
+    let mut selected = Vec::new();
+    for part in input.split(|c: char| c.is_whitespace() || c == ',') {
+        if part.is_empty() {
+            continue;
+        }
+        let (start, end) = match part.split_once('-') {
+            Some((a, b)) => (a.parse::<usize>()?, b.parse::<usize>()?),
+            None => {
+                let n = part.parse::<usize>()?;
+                (n, n)
+            }
+        };
+        if start == 0 || end > count || start > end {
+            anyhow::bail!("Invalid selection '{}' (choose 1-{})", part, count);
+        }
+        for i in start - 1..end {
+            if !selected.contains(&i) {
+                selected.push(i);
+            }
+        }
+    }
+    Ok(selected)
+}
+
+/// Clap parser for ages like `7d`: seconds
+fn parse_age(age: &str) -> Result<u64, String> {
+    timestamp::parse_age(age)
+        .ok_or_else(|| format!("invalid age '{}' (e.g. 30m, 12h, 7d, 2w)", age))
+}
+
+/// Clap parser for download rates like `500k`
+fn parse_rate(rate: &str) -> Result<String, String> {
+    disk::parse_size(rate)
+        .map(|_| rate.to_string())
+        .ok_or_else(|| format!("invalid rate '{}' (e.g. 500k, 2M)", rate))
+}
+
+/// Which installed packages to list, and in what order
+struct ListOptions {
+    foreign: bool,
+    /// Maximum age in seconds
+    newer_than: Option<u64>,
+    /// Minimum age in seconds
+    older_than: Option<u64>,
+    sort: Option<ListSort>,
+    reverse: bool,
+}
+
+/// Installed packages, filtered and ordered as requested
+fn sorted_installed<'a>(
+    cfg: &config::Config,
+    db: &'a db::Database,
+    opts: &ListOptions,
+) -> Vec<&'a db::InstalledPackage> {
+    let mut installed = if opts.foreign {
+        db.foreign_packages()
+    } else {
+        db.list_installed()
+    };
+    let now = timestamp::now_secs();
+    if let Some(age) = opts.newer_than {
+        let cutoff = now.saturating_sub(age);
+        installed.retain(|pkg| pkg.installed_secs().is_some_and(|t| t >= cutoff));
+    }
+    if let Some(age) = opts.older_than {
+        let cutoff = now.saturating_sub(age);
+        installed.retain(|pkg| pkg.installed_secs().is_some_and(|t| t < cutoff));
+    }
+    // list_installed is already by name, and the sorts are stable, so ties stay by name
+    match opts.sort {
+        Some(ListSort::Name) | None => {}
+        Some(ListSort::Version) => {
+            installed.sort_by(|a, b| version::compare(&a.version, &b.version))
+        }
+        Some(ListSort::Date) => installed.sort_by_key(|p| p.installed_secs().unwrap_or(0)),
+        Some(ListSort::Size) => installed.sort_by_key(|p| p.installed_size(p.root(cfg))),
+    }
+    if opts.reverse {
+        installed.reverse();
+    }
+    installed
+}
+
+fn list_packages(
+    cfg: &config::Config,
+    format: Option<&str>,
+    no_align: bool,
+    opts: &ListOptions,
+    pager: bool,
+) -> Result<()> {
+    let db = db::Database::load(cfg)?;
+    let installed = sorted_installed(cfg, &db, opts);
+
+    if installed.is_empty() {
+        if opts.foreign {
+            println!("No foreign packages installed");
+        } else {
+            println!("No packages installed");
+        }
+        return Ok(());
+    }
+
+    let mut out = String::new();
+    for pkg in installed {
+        if let Some(template) = format {
+            writeln!(out, "{}", format_installed(&db, pkg, template))?;
+        } else if no_align {
+            writeln!(out, "{}\t{}\t{}", pkg.name, pkg.version, pkg.installed_at)?;
+        } else {
+            writeln!(out, "{:<20} {:<12} {}", pkg.name.bold(), pkg.version, pkg.installed_at)?;
+        }
+    }
+    pager::page(&out, pager)
+}
+
+fn list_group(cfg: &config::Config, group: &str, no_align: bool, pager: bool) -> Result<()> {
+    let db = db::Database::load(cfg)?;
+    let members = db.group_members(group);
+    if members.is_empty() {
+        anyhow::bail!(error::Error::NotFound(format!("No packages in group '{}'", group)));
+    }
+
+    let mut out = String::new();
+    for pkg in members {
+        let installed = db.get_installed(&pkg.name).is_some();
+        if no_align {
+            let status = if installed { "installed" } else { "" };
+            writeln!(out, "{}\t{}\t{}", pkg.name, pkg.version, status)?;
+        } else {
+            let status = if installed { "[installed]".green() } else { "".normal() };
+            writeln!(out, "{:<20} {:<12} {}", pkg.name.bold(), pkg.version, status)?;
+        }
+    }
+    pager::page(&out, pager)
+}
+
+fn list_cached(cfg: &config::Config, no_align: bool, pager: bool) -> Result<()> {
+    let db = db::Database::load(cfg)?;
+    let files = fetch::cached_files(cfg)?;
+    if files.is_empty() {
+        println!("No packages in the download cache");
+        return Ok(());
+    }
+
+    let mut out = String::new();
+    let mut total = 0;
+    for (filename, size) in &files {
+        total += size;
+        // Old versions are no longer in the repo; those show just the filename
+        let pkg = db.packages.iter().find(|p| p.filename == *filename);
+        let (name, version) = pkg.map_or((filename.as_str(), "-"), |p| (&p.name, &p.version));
+        let status = match pkg {
+            Some(p) if db.get_installed(&p.name).is_some_and(|i| i.version == p.version) => {
+                "installed"
+            }
+            Some(_) => "in repo",
+            None => "",
+        };
+        if no_align {
+            writeln!(out, "{}\t{}\t{}\t{}", name, version, size, status)?;
+        } else {
+            writeln!(
+                out,
+                "{:<20} {:<12} {:>8} {}",
+                name.bold(),
+                version,
+                disk::format_size(*size),
+                status.green()
+            )?;
+        }
+    }
+    if !no_align {
+        writeln!(
+            out,
+            "{} {} files, {}",
+            "==>".green().bold(),
+            files.len(),
+            disk::format_size(total)
+        )?;
+    }
+    pager::page(&out, pager)
+}
+
+fn clean_cache(cfg: &config::Config, rollback: bool) -> Result<()> {
+    let dir = cfg.cache_dir.join("packages");
+    let mut removed: Vec<_> = fetch::cached_files(cfg)?
+        .into_iter()
+        .map(|(filename, size)| (dir.join(filename), size))
+        .collect();
+    let kept = rollback::kept(cfg)?;
+    if rollback {
+        removed.extend(kept.iter().map(|k| (k.path.clone(), k.size)));
+    }
+    for (path, _) in &removed {
+        std::fs::remove_file(path)
+            .with_context(|| format!("Failed to remove {}", path.display()))?;
+    }
+    // The rollback tarballs' version and name directories, once emptied
+    for k in kept.iter().filter(|_| rollback) {
+        for dir in k.path.ancestors().skip(1).take(2) {
+            std::fs::remove_dir(dir).ok();
+        }
+    }
+
+    let freed = removed.iter().map(|(_, size)| size).sum();
+    println!(
+        "{} Removed {} tarballs, {} freed",
+        "==>".green().bold(),
+        removed.len(),
+        disk::format_size(freed)
+    );
+    let (pruned, pruned_size) = store::prune(cfg)?;
+    if pruned > 0 {
+        eprintln!(
+            "  -> Pruned {} unused store entries ({} freed)",
+            pruned,
+            disk::format_size(pruned_size)
+        );
+    }
+    if !rollback && !kept.is_empty() {
+        eprintln!(
+            "  -> Kept {} tarballs for rollback (use --rollback to remove them)",
+            kept.len()
+        );
+    }
+    Ok(())
+}
+
+/// Expand a `list --format` template for one installed package
+fn format_installed(db: &db::Database, pkg: &db::InstalledPackage, template: &str) -> String {
+    let size = db
+        .find_package(&pkg.name)
+        .map(|p| p.size)
+        .unwrap_or_else(|_| "-".to_string());
+    template
+        .replace("{name}", &pkg.name)
+        .replace("{version}", &pkg.version)
+        .replace("{date}", &pkg.installed_at)
+        .replace("{size}", &size)
+        .replace("{files}", &pkg.file_entries().count().to_string())
+        .replace("\\t", "\t")
+}
+
+/// Report what a package tarball holds without installing it, and check
+/// that its `.PKGINFO` (if any) agrees with the archive
+fn inspect_package(cfg: &config::Config, path: &std::path::Path, pager: bool) -> Result<()> {
+    if !path.is_file() {
+        anyhow::bail!(error::Error::NotFound(format!("{} not found", path.display())));
+    }
+    let members = package::list_members(path)?;
+    let meta = package::read_pkginfo(path).ok();
+
+    let mut out = String::new();
+    writeln!(out, "{:<14} {}", "File:".bold(), path.display())?;
+    writeln!(out, "{:<14} {}", "Size:".bold(), disk::format_size(std::fs::metadata(path)?.len()))?;
+    writeln!(out, "{:<14} {}", "SHA256:".bold(), verify::sha256_file(path)?)?;
+    let files: Vec<_> = members.iter().filter(|m| !m.is_dir).collect();
+    writeln!(
+        out,
+        "{:<14} {} files, {} unpacked",
+        "Contents:".bold(),
+        files.len(),
+        disk::format_size(files.iter().map(|m| m.size).sum())
+    )?;
+
+    let mut problems = Vec::new();
+    let mut warnings = Vec::new();
+    match &meta {
+        None => warnings.push(format!("no {} metadata, nothing to check", package::PKGINFO)),
+        Some(meta) => {
+            writeln!(out, "{:<14} {} {}", "Package:".bold(), meta.name, meta.version)?;
+            writeln!(out, "{:<14} {}", "Arch:".bold(), meta.arch)?;
+            let depends = if meta.depends.is_empty() {
+                "none".to_string()
+            } else {
+                meta.depends.join(", ")
+            };
+            writeln!(out, "{:<14} {}", "Depends:".bold(), depends)?;
+            check_manifest(cfg, path, meta, &members, &mut problems, &mut warnings);
+        }
+    }
+
+    writeln!(out)?;
+    for member in &members {
+        let size = if member.is_dir { "-".to_string() } else { disk::format_size(member.size) };
+        writeln!(out, "  {:>8} /{}", size, member.path)?;
+    }
+    pager::page(&out, pager)?;
+
+    for warning in &warnings {
+        eprintln!("{} {}", "warning:".yellow().bold(), warning);
+    }
+    for problem in &problems {
+        eprintln!("  -> {}", problem);
+    }
+    if !problems.is_empty() {
+        anyhow::bail!(error::Error::Verification(format!(
+            "{} problems in {}",
+            problems.len(),
+            path.display()
+        )));
+    }
+    if meta.is_some() {
+        println!("{} {} looks consistent", "==>".green().bold(), path.display());
+    }
+    Ok(())
+}
+
+/// Compare a tarball's `.PKGINFO` with what the archive really holds: the
+/// file manifest and hashes, the dependencies and the filename
+fn check_manifest(
+    cfg: &config::Config,
+    path: &std::path::Path,
+    meta: &package::PkgMeta,
+    members: &[package::Member],
+    problems: &mut Vec<String>,
+    warnings: &mut Vec<String>,
+) {
+    if meta.files.is_empty() {
+        warnings.push(format!("{} has no file manifest", package::PKGINFO));
+    } else {
+        let shipped: HashMap<&str, Option<&str>> = members
+            .iter()
+            .filter(|m| !m.is_dir)
+            .map(|m| (m.path.as_str(), m.sha256.as_deref()))
+            .collect();
+        for (file, hash) in &meta.files {
+            match shipped.get(file.as_str()) {
+                None => problems.push(format!("{} is listed but not in the archive", file)),
+                Some(Some(actual)) if actual != hash => {
+                    problems.push(format!("{} doesn't match its listed sha256", file))
+                }
+                Some(_) => {}
+            }
+        }
+        for member in members {
+            if member.sha256.is_some() && !meta.files.contains_key(&member.path) {
+                problems.push(format!("{} is in the archive but not listed", member.path));
+            }
+        }
+    }
+
+    let repo = db::Database::load(cfg).ok();
+    for entry in &meta.depends {
+        let (cond, dep) = db::split_depend(entry);
+        if dep.is_empty() || cond == Some("") {
+            problems.push(format!("malformed dependency '{}'", entry));
+        } else if dep == meta.name {
+            problems.push(format!("{} depends on itself", meta.name));
+        } else if let Some(db) = &repo
+            && !db.packages.iter().any(|p| p.name == dep || p.provides.iter().any(|v| v == dep))
+        {
+            warnings.push(format!("dependency '{}' is not in the synced repos", dep));
+        }
+    }
+
+    let expected = format!("{}-{}-{}.tar", meta.name, meta.version, meta.arch);
+    let filename = path.file_name().unwrap_or_default().to_string_lossy();
+    if !filename.starts_with(&expected) {
+        warnings.push(format!("filename {} doesn't match {}.*", filename, expected));
+    }
+}
+
+/// Run `show` for each package, with a blank line between them. With
+/// `keep_going`, a failure is reported and the rest are still shown; the
+/// first failure is returned at the end.
+fn for_each_package(
+    packages: &[String],
+    keep_going: bool,
+    show: impl Fn(&str) -> Result<()>,
+) -> Result<()> {
+    let mut failures = Vec::new();
+    for (i, package) in packages.iter().enumerate() {
+        if i > 0 {
+            println!();
+        }
+        match show(package) {
+            Ok(()) => {}
+            Err(e) if keep_going => {
+                eprintln!("{} {}: {:#}", "error:".red().bold(), package, e);
+                failures.push(e);
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    match failures.len() {
+        0 => Ok(()),
+        n => Err(failures.swap_remove(0).context(format!(
+            "{} of {} packages could not be shown",
+            n,
+            packages.len()
+        ))),
+    }
+}
+
+/// List the `top` largest files an installed package owns, with their share
+/// of its total size on disk
+fn show_size_breakdown(cfg: &config::Config, name: &str, top: usize) -> Result<()> {
+    let db = db::Database::load(cfg)?;
+    let Some(installed) = db.get_installed(name) else {
+        anyhow::bail!(error::Error::NotFound(format!("{} is not installed", name)));
+    };
+
+    let mut sizes = Vec::new();
+    let mut missing = 0;
+    for file in installed.file_entries() {
+        match std::fs::symlink_metadata(installed.root(cfg).join(file)) {
+            Ok(meta) if meta.is_file() => sizes.push((meta.len(), file)),
+            Ok(_) => {}
+            Err(_) => missing += 1,
+        }
+    }
+    sizes.sort_by(|a, b| b.0.cmp(&a.0).then_with(|| a.1.cmp(b.1)));
+    let total: u64 = sizes.iter().map(|(size, _)| size).sum();
+
+    println!(
+        "{} {} {}: {} files, {} on disk",
+        "==>".green().bold(),
+        installed.name.bold(),
+        installed.version,
+        sizes.len(),
+        disk::format_size(total)
+    );
+    for (size, file) in sizes.iter().take(top) {
+        let share = if total == 0 { 0.0 } else { *size as f64 * 100.0 / total as f64 };
+        println!("  {:>8} {:>5.1}%  /{}", disk::format_size(*size), share, file);
+    }
+    if sizes.len() > top {
+        let rest: u64 = sizes[top..].iter().map(|(size, _)| size).sum();
+        println!(
+            "  {:>8}         {}",
+            disk::format_size(rest),
+            format!("(the other {} files)", sizes.len() - top).dimmed()
+        );
+    }
+    if missing > 0 {
+        eprintln!(
+            "{} {} files are missing (see 'scarab verify')",
+            "warning:".yellow().bold(),
+            missing
+        );
+    }
+    Ok(())
+}
+
+fn show_tree(cfg: &config::Config, name: &str, depth: Option<usize>) -> Result<()> {
+    let db = db::Database::load(cfg)?;
+    let pkg = db.find_package(name)?;
+    println!("{} {}", pkg.name.bold(), pkg.version);
+
+    let mut path = vec![pkg.name.clone()];
+    let mut shown = std::collections::HashSet::new();
+    print_deps(&db, &pkg, "", depth.unwrap_or(usize::MAX), &mut path, &mut shown);
+    Ok(())
+}
+
+/// Print `pkg`'s dependencies below it, marking cycles and subtrees that were
+/// already expanded elsewhere instead of repeating them
+fn print_deps(
+    db: &db::Database,
+    pkg: &package::PackageInfo,
+    prefix: &str,
+    depth: usize,
+    path: &mut Vec<String>,
+    shown: &mut std::collections::HashSet<String>,
+) {
+    if depth == 0 {
+        return;
+    }
+    let depends = db.active_depends(&pkg.depends);
+    for (i, dep) in depends.iter().enumerate() {
+        let last = i + 1 == depends.len();
+        let (branch, indent) = if last { ("└── ", "    ") } else { ("├── ", "│   ") };
+
+        let Ok(dep_pkg) = db.find_package(dep) else {
+            println!("{}{}{} {}", prefix, branch, dep, "(not found)".red());
+            continue;
+        };
+        if path.contains(dep) {
+            println!("{}{}{} {}", prefix, branch, dep, "(cycle)".yellow());
+            continue;
+        }
+        let leaf = db.active_depends(&dep_pkg.depends).is_empty();
+        if leaf || depth == 1 || shown.insert(dep.clone()) {
+            println!("{}{}{} {}", prefix, branch, dep, dep_pkg.version.dimmed());
+        } else {
+            println!("{}{}{} {} {}", prefix, branch, dep, dep_pkg.version.dimmed(), "(see above)".dimmed());
+            continue;
+        }
+
+        path.push(dep.clone());
+        print_deps(db, &dep_pkg, &format!("{}{}", prefix, indent), depth - 1, path, shown);
+        path.pop();
+    }
+}
+
+fn show_info(
+    cfg: &config::Config,
+    name: &str,
+    files: bool,
+    deps: bool,
+    remote: bool,
+    all_versions: bool,
+) -> Result<()> {
+    let mut db = db::Database::load(cfg)?;
+    // With --remote, the synced entry is kept only to compare against
+    let synced = if remote {
+        let synced = db.find_package(name).ok();
+        db.packages = fetch::remote_packages(cfg)?;
+        Some(synced)
+    } else {
+        None
+    };
+    let pkg = db.find_package(name)?;
+
+    println!("{:<14} {}", "Name:".bold(), pkg.name);
+    println!("{:<14} {}", "Version:".bold(), pkg.version);
+    if all_versions {
+        let installed = db.get_installed(&pkg.name).map(|i| i.version.as_str());
+        let versions: Vec<_> = db
+            .versions(&pkg.name)
+            .into_iter()
+            .map(|p| {
+                let mark = if installed == Some(p.version.as_str()) { ", installed" } else { "" };
+                format!("{} ({}{})", p.version, p.repo, mark)
+            })
+            .collect();
+        println!("{:<14} {}", "All versions:".bold(), versions.join(", "));
+    }
+    println!("{:<14} {}", "Repository:".bold(), pkg.repo);
+    println!("{:<14} {}", "Category:".bold(), pkg.category);
+    println!("{:<14} {}", "Description:".bold(), pkg.description);
+    println!(
+        "{:<14} {}",
+        "Depends:".bold(),
+        if pkg.depends.is_empty() {
+            "none".to_string()
+        } else {
+            pkg.depends.join(", ")
+        }
+    );
+    if !pkg.recommends.is_empty() {
+        println!("{:<14} {}", "Recommends:".bold(), pkg.recommends.join(", "));
+    }
+    let closure = db.dependency_closure(&pkg);
+    if !closure.is_empty() {
+        let missing = closure.iter().filter(|d| d.installed.is_none()).count();
+        println!(
+            "{:<14} {} ({} not installed)",
+            "All deps:".bold(),
+            closure.len(),
+            missing
+        );
+    }
+    if !pkg.groups.is_empty() {
+        println!("{:<14} {}", "Groups:".bold(), pkg.groups.join(", "));
+    }
+    println!("{:<14} {}", "Size:".bold(), pkg.size);
+    if !pkg.is_meta() {
+        let cached = fetch::cache_path(cfg, &pkg).exists();
+        println!("{:<14} {}", "Cached:".bold(), if cached { "yes" } else { "no" });
+    }
+
+    if let Some(installed) = db.get_installed(name) {
+        println!(
+            "{:<14} {} ({})",
+            "Status:".bold(),
+            "installed".green(),
+            installed.version
+        );
+    } else {
+        println!("{:<14} {}", "Status:".bold(), "not installed".yellow());
+    }
+    if let Some(synced) = synced {
+        let status = match synced {
+            Some(local) if local.version == pkg.version => "up to date".green(),
+            Some(local) => format!("{} (run 'scarab sync' to update)", local.version).yellow(),
+            None => "not synced yet".yellow(),
+        };
+        println!("{:<14} {}", "Local db:".bold(), status);
+    }
 
-    match cli.command {
-        Commands::Install { packages, force } => {
-            for pkg in &packages {
-                install_package(&cfg, pkg, force)?;
+    if files {
+        match db.get_installed(name) {
+            Some(installed) => {
+                println!("{:<14} {}", "Files:".bold(), installed.file_entries().count());
+                for file in installed.file_entries() {
+                    println!("  /{}", file);
+                }
             }
+            None => println!(
+                "{:<14} {}",
+                "Files:".bold(),
+                "not installed (file list unavailable)".dimmed()
+            ),
         }
-        Commands::Remove { packages } => {
-            for pkg in &packages {
-                remove_package(&cfg, pkg)?;
-            }
+    }
+
+    if deps {
+        for dep in &closure {
+            let status = match (&dep.installed, &dep.available) {
+                (Some(installed), _) => format!("installed ({})", installed).green(),
+                (None, Some(available)) => format!("not installed ({})", available).yellow(),
+                (None, None) => "not in repo".red(),
+            };
+            let name = match &dep.provides {
+                Some(virtual_name) => format!("{} ({})", dep.name, virtual_name),
+                None => dep.name.clone(),
+            };
+            println!("  {:<20} {}", name, status);
         }
-        Commands::Search { query } => search_packages(&cfg, &query)?,
-        Commands::List => list_packages(&cfg)?,
-        Commands::Info { package } => show_info(&cfg, &package)?,
-        Commands::Sync => sync_db(&cfg)?,
-        Commands::Upgrade => upgrade_packages(&cfg)?,
-        Commands::Build { package } => build_package(&cfg, &package)?,
     }
 
     Ok(())
 }
 
-fn install_package(cfg: &config::Config, name: &str, force: bool) -> Result<()> {
-    let db = db::Database::load(cfg)?;
+fn sync_db(cfg: &config::Config) -> Result<()> {
+    println!("{} Syncing package database...", "==>".green().bold());
+    fetch::sync_repo_db(cfg)?;
+    println!("{} Database synced", "==>".green().bold());
+    Ok(())
+}
 
-    if !force {
-        if let Some(installed) = db.get_installed(name) {
+fn sync_ports(cfg: &config::Config) -> Result<()> {
+    println!("{} Syncing ports tree...", "==>".green().bold());
+    match ports::sync(cfg)? {
+        ports::Synced::Cloned(count) => println!(
+            "{} Cloned {} ports into {}",
+            "==>".green().bold(),
+            count,
+            cfg.ports_dir.display()
+        ),
+        ports::Synced::Pulled { from, to, .. } if from == to => {
+            println!("{} Ports tree is up to date", "==>".green().bold())
+        }
+        ports::Synced::Pulled { from, to, changes } => {
+            for (port, change) in &changes {
+                let label = match change {
+                    ports::Change::Added => "added".green(),
+                    ports::Change::Updated => "updated".yellow(),
+                    ports::Change::Removed => "removed".red(),
+                };
+                println!("  {:<8} {}", label, port);
+            }
             println!(
-                "{} {} {} is already installed (use -f to force)",
+                "{} Ports tree updated {}..{}: {} ports changed",
                 "==>".green().bold(),
-                name.bold(),
-                installed.version
+                &from[..from.len().min(7)],
+                &to[..to.len().min(7)],
+                changes.len()
+            );
+        }
+    }
+    Ok(())
+}
+
+fn warn_if_stale(cfg: &config::Config) {
+    if cfg.stale_days == 0 {
+        return;
+    }
+    if let Some(age) = fetch::last_sync_age(cfg) {
+        let days = age.as_secs() / 86400;
+        if days >= cfg.stale_days {
+            eprintln!(
+                "{} database is {} days old; consider 'scarab sync'",
+                "warning:".yellow().bold(),
+                days
+            );
+        }
+    }
+}
+
+fn upgrade_packages(
+    cfg: &config::Config,
+    ignore: &[String],
+    noconfirm: bool,
+    reinstall_changed: bool,
+    force: bool,
+) -> Result<()> {
+    let mut db = db::Database::load(cfg)?;
+    let mut candidates = db.check_upgrades();
+    if reinstall_changed {
+        // Same version on both sides marks a rebuild
+        let rebuilt = db.check_rebuilds().into_iter();
+        candidates.extend(rebuilt.map(|(name, version)| (name, version.clone(), version)));
+    }
+    let (skipped, upgrades): (Vec<_>, Vec<_>) = candidates
+        .into_iter()
+        .partition(|(name, _, _)| ignore.contains(name));
+
+    for (name, old_ver, new_ver) in &skipped {
+        println!(
+            "  {} {} -> {} (ignored)",
+            name.dimmed(),
+            old_ver.dimmed(),
+            new_ver.dimmed()
+        );
+    }
+
+    let foreign = db.foreign_packages();
+    if !foreign.is_empty() {
+        let names: Vec<_> = foreign.iter().map(|p| p.name.as_str()).collect();
+        eprintln!(
+            "{} Not in repo, will not be upgraded: {}",
+            "warning:".yellow().bold(),
+            names.join(", ")
+        );
+    }
+
+    if upgrades.is_empty() {
+        println!("{} System is up to date", "==>".green().bold());
+        return Ok(());
+    }
+
+    // Upgrades reinstall over existing versions, but never skip the space
+    // check; `force` only lets unresolvable dependencies through
+    let names: Vec<_> = upgrades.iter().map(|(name, _, _)| name).collect();
+    let opts = InstallOptions {
+        yes: noconfirm,
+        allow_unresolved: force,
+        ..InstallOptions::default()
+    };
+    let plan = resolve_plan(&mut db, &names, true, &opts)?;
+
+    // Installed size change per package; None when the old size wasn't recorded
+    let size = |s: &str| disk::parse_size(s).map(|n| n as i64);
+    let delta = |pkg: &package::PackageInfo| match db.get_installed(&pkg.name) {
+        Some(old) => size(&pkg.size).zip(size(&old.size)).map(|(new, old)| new - old),
+        None => size(&pkg.size),
+    };
+    let show = |d: Option<i64>| d.map_or("?".to_string(), disk::format_delta);
+
+    let (upgraded, added): (Vec<_>, Vec<_>) =
+        plan.iter().partition(|p| db.get_installed(&p.name).is_some());
+    println!("{} Upgrades ({}):", "==>".green().bold(), upgraded.len());
+    for pkg in &upgraded {
+        let old = db.get_installed(&pkg.name).map_or("", |p| p.version.as_str());
+        if old == pkg.version {
+            println!("  {:<20} {} (rebuilt)", pkg.name.bold(), pkg.version.green());
+            continue;
+        }
+        println!(
+            "  {:<20} {} -> {}  {}",
+            pkg.name.bold(),
+            old.dimmed(),
+            pkg.version.green(),
+            show(delta(pkg)).dimmed()
+        );
+    }
+    if !added.is_empty() {
+        println!("{} New dependencies ({}):", "==>".green().bold(), added.len());
+        for pkg in &added {
+            println!(
+                "  {:<20} {}  {}",
+                pkg.name.bold(),
+                pkg.version.green(),
+                show(delta(pkg)).dimmed()
             );
+        }
+    }
+    let known: Vec<_> = plan.iter().filter_map(delta).collect();
+    let total = disk::format_delta(known.iter().sum());
+    if known.len() < plan.len() {
+        println!("{} Installed size change: {} (some sizes unknown)", "==>".green().bold(), total);
+    } else {
+        println!("{} Installed size change: {}", "==>".green().bold(), total);
+    }
+
+    if !noconfirm {
+        let answer = prompt("==> Proceed with upgrade? [Y/n] ")?;
+        let yes = answer.is_some_and(|a| matches!(a.to_lowercase().as_str(), "" | "y" | "yes"));
+        if !yes {
+            println!("Upgrade cancelled");
             return Ok(());
         }
     }
 
-    // Find package in repo
-    let pkg = db.find_package(name)?;
+    execute_plan(cfg, &plan, &[], false, &opts)
+}
+
+/// Concurrent downloads when `jobs` is left to the default, which follows the
+/// CPU count and has little to do with what a connection can take
+const DOWNLOAD_JOBS: usize = 4;
+
+/// Download and verify the tarball of every package in `plan`, `jobs` at
+/// once (one at a time under a `download_limit`, which is per connection),
+/// returning where each package's verified tarball is. After the first
+/// failure no new downloads start, and its error is returned once the
+/// running ones finish.
+fn fetch_plan(
+    cfg: &config::Config,
+    plan: &[package::PackageInfo],
+    opts: &InstallOptions,
+) -> Result<HashMap<String, std::path::PathBuf>> {
+    let pending: Vec<_> = plan.iter().filter(|p| !p.is_meta()).collect();
+    let jobs = match cfg.jobs {
+        _ if !cfg.download_limit.is_empty() => 1,
+        0 => DOWNLOAD_JOBS,
+        n => n,
+    };
+    let jobs = jobs.clamp(1, pending.len().max(1));
     println!(
-        "{} Installing {} {}...",
+        "{} Downloading {} packages, up to {} at once...",
         "==>".green().bold(),
-        pkg.name.bold(),
-        pkg.version
+        pending.len(),
+        jobs
     );
 
-    // Resolve dependencies
-    let deps = db.resolve_deps(&pkg)?;
-    if !deps.is_empty() {
-        println!("{} Dependencies: {}", "  ->".blue(), deps.join(", "));
-        for dep in &deps {
-            install_package(cfg, dep, false)?;
+    let size = |p: &package::PackageInfo| disk::parse_size(&p.size).unwrap_or(0);
+    let total = disk::format_size(pending.iter().map(|p| size(p)).sum());
+    let spinner = progress::Spinner::start(&format!("0/{} packages", pending.len()));
+    let next = AtomicUsize::new(0);
+    let fetched = Mutex::new(HashMap::new());
+    let bytes = AtomicU64::new(0);
+    let failed = Mutex::new(None);
+    let stop = AtomicBool::new(false);
+    std::thread::scope(|scope| {
+        for _ in 0..jobs {
+            scope.spawn(|| {
+                while !stop.load(Ordering::Relaxed)
+                    && let Some(pkg) = pending.get(next.fetch_add(1, Ordering::Relaxed))
+                {
+                    match fetch_verified(cfg, pkg, opts) {
+                        Ok(tarball) => {
+                            let mut fetched = fetched.lock().unwrap();
+                            fetched.insert(pkg.name.clone(), tarball);
+                            let done = bytes.fetch_add(size(pkg), Ordering::Relaxed) + size(pkg);
+                            spinner.set_message(format!(
+                                "{}/{} packages, {} of {}",
+                                fetched.len(),
+                                pending.len(),
+                                disk::format_size(done),
+                                total
+                            ));
+                        }
+                        Err(e) => {
+                            stop.store(true, Ordering::Relaxed);
+                            eprintln!("  -> {} {} failed", pkg.name, pkg.version);
+                            failed.lock().unwrap().get_or_insert(e);
+                        }
+                    }
+                }
+            });
         }
+    });
+    drop(spinner);
+
+    if let Some(e) = failed.into_inner().unwrap() {
+        eprintln!("  -> Nothing was changed");
+        return Err(e);
+    }
+    eprintln!("  -> Downloaded and verified {} packages ({})", pending.len(), total);
+    Ok(fetched.into_inner().unwrap())
+}
+
+fn diff_packages(cfg: &config::Config, pager: bool) -> Result<()> {
+    let db = db::Database::load(cfg)?;
+    let mut out = String::new();
+
+    writeln!(
+        out,
+        "{} Installed            Local        Repo         Status",
+        "==>".green().bold()
+    )?;
+    for pkg in db.list_installed() {
+        let (repo_version, status) = match db.find_package(&pkg.name) {
+            Ok(repo_pkg) => {
+                let status = match version::compare(&repo_pkg.version, &pkg.version) {
+                    std::cmp::Ordering::Equal => "up to date".dimmed(),
+                    std::cmp::Ordering::Greater => "upgrade".green(),
+                    std::cmp::Ordering::Less => "newer than repo".yellow(),
+                };
+                (repo_pkg.version, status)
+            }
+            Err(_) => ("-".to_string(), "foreign/dropped".yellow()),
+        };
+        writeln!(
+            out,
+            "    {:<20} {:<12} {:<12} {}",
+            pkg.name.bold(),
+            pkg.version,
+            repo_version,
+            status
+        )?;
     }
 
-    // Download
-    let tarball = fetch::download_package(cfg, &pkg)?;
+    writeln!(out, "{} Available (not installed)", "==>".green().bold())?;
+    // Each name once, at the version `install` would pick
+    let available: BTreeSet<_> = db
+        .packages
+        .iter()
+        .map(|p| p.name.as_str())
+        .filter(|name| db.get_installed(name).is_none())
+        .collect();
+    for pkg in available.into_iter().filter_map(|name| db.lookup(name)) {
+        writeln!(out, "    {:<20} -            {}", pkg.name.bold(), pkg.version)?;
+    }
 
-    // Verify
-    verify::verify_package(&tarball, &pkg)?;
+    pager::page(&out, pager)
+}
 
-    // Extract to root
-    package::extract_package(&tarball, &cfg.root)?;
+fn verify_installed(
+    cfg: &config::Config,
+    packages: &[String],
+    all: bool,
+    jobs: usize,
+    fix: bool,
+    full: bool,
+) -> Result<()> {
+    let db = db::Database::load(cfg)?;
+    let targets = verify_targets(&db, packages, all)?;
 
-    // Record installation
-    let mut db = db::Database::load(cfg)?;
-    db.record_install(&pkg)?;
+    let mut checks = Vec::new();
+    for pkg in &targets {
+        if pkg.file_hashes.is_empty() {
+            eprintln!("  -> {}: no checksums recorded, skipping", pkg.name);
+        }
+        for (path, hash) in &pkg.file_hashes {
+            checks.push(verify::FileCheck {
+                package: pkg.name.clone(),
+                root: pkg.root(cfg).to_path_buf(),
+                path: path.clone(),
+                expected: hash.clone(),
+            });
+        }
+    }
 
     println!(
-        "{} Installed {} {}",
+        "{} Verifying {} files from {} packages...",
         "==>".green().bold(),
-        pkg.name.bold(),
-        pkg.version
+        checks.len(),
+        targets.len()
     );
+    // A full run still refreshes the cache for the next one
+    let cache = cfg.verify_cache.then(|| {
+        let mut cache = verify::HashCache::load(cfg);
+        if full {
+            checks.iter().for_each(|c| cache.forget(&c.root.join(&c.path)));
+        }
+        Mutex::new(cache)
+    });
+    let problems = verify::check_files(&checks, jobs, cache.as_ref());
+    if let Some(cache) = cache
+        && let Err(e) = cache.into_inner().unwrap().save(cfg)
+    {
+        eprintln!("{} Cannot save the verify cache: {:#}", "warning:".yellow().bold(), e);
+    }
+
+    for (check, status) in &problems {
+        let label = match status {
+            verify::FileStatus::Missing => "missing".red(),
+            verify::FileStatus::Modified => "modified".yellow(),
+        };
+        println!("  {} {} /{}", check.package.bold(), label, check.path);
+    }
+
+    if fix && !problems.is_empty() {
+        let broken: BTreeSet<_> = problems.iter().map(|(check, _)| check.package.as_str()).collect();
+        let broken: Vec<_> = broken.into_iter().collect();
+        return reinstall_packages(cfg, &db, &broken, |names| {
+            error::Error::Verification(format!("Could not repair: {}", names))
+        });
+    }
+
+    if !problems.is_empty() {
+        anyhow::bail!(error::Error::Verification(format!(
+            "{} files failed verification",
+            problems.len()
+        )));
+    }
+    println!("{} All files OK", "==>".green().bold());
     Ok(())
 }
 
-fn remove_package(cfg: &config::Config, name: &str) -> Result<()> {
-    let mut db = db::Database::load(cfg)?;
-
-    let installed = db
-        .get_installed(name)
-        .ok_or_else(|| anyhow::anyhow!("{} is not installed", name))?
-        .clone();
+/// The installed packages `verify` looks at: those named, or all of them
+fn verify_targets<'a>(
+    db: &'a db::Database,
+    packages: &[String],
+    all: bool,
+) -> Result<Vec<&'a db::InstalledPackage>> {
+    if all || packages.is_empty() {
+        return Ok(db.list_installed());
+    }
+    packages
+        .iter()
+        .map(|name| {
+            db.get_installed(name)
+                .ok_or_else(|| error::Error::NotFound(format!("{} is not installed", name)).into())
+        })
+        .collect()
+}
 
+/// Report installed symlinks whose targets don't exist under the package's
+/// root, and with `fix` delete them and stop tracking them
+fn verify_dangling(cfg: &config::Config, packages: &[String], all: bool, fix: bool) -> Result<()> {
+    let mut db = db::Database::load(cfg)?;
+    let targets = verify_targets(&db, packages, all)?;
     println!(
-        "{} Removing {} {}...",
+        "{} Checking symlinks of {} packages...",
         "==>".green().bold(),
-        name.bold(),
-        installed.version
+        targets.len()
     );
 
-    // Remove files
-    package::remove_package_files(cfg, &installed)?;
-
-    // Remove from db
-    db.remove_installed(name)?;
+    let mut dangling: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    for pkg in &targets {
+        let root = pkg.root(cfg);
+        for file in &pkg.files {
+            let path = root.join(file);
+            if std::fs::symlink_metadata(&path).is_ok_and(|m| m.is_symlink())
+                && !verify::link_resolves(root, &path)
+            {
+                let target = std::fs::read_link(&path).unwrap_or_default();
+                let target = target.display().to_string();
+                println!("  {} /{} -> {}", pkg.name.bold(), file, target.red());
+                dangling.entry(pkg.name.clone()).or_default().push(file.clone());
+            }
+        }
+    }
 
-    println!("{} Removed {}", "==>".green().bold(), name.bold());
+    let count: usize = dangling.values().map(Vec::len).sum();
+    if count == 0 {
+        println!("{} No dangling symlinks", "==>".green().bold());
+        return Ok(());
+    }
+    if !fix {
+        anyhow::bail!(error::Error::Verification(format!(
+            "{} dangling symlinks (use --fix to remove them)",
+            count
+        )));
+    }
+    for (name, files) in &dangling {
+        let root = db.get_installed(name).map(|p| p.root(cfg).to_path_buf()).unwrap_or_default();
+        for file in files {
+            std::fs::remove_file(root.join(file))
+                .with_context(|| format!("Failed to remove /{}", file))?;
+        }
+        db.forget_files(name, files)?;
+    }
+    println!("{} Removed {} dangling symlinks", "==>".green().bold(), count);
     Ok(())
 }
 
-fn search_packages(cfg: &config::Config, query: &str) -> Result<()> {
+fn verify_repo(cfg: &config::Config) -> Result<()> {
     let db = db::Database::load(cfg)?;
-    let results = db.search(query);
+    let issues = db.check_repo();
 
-    if results.is_empty() {
-        println!("No packages found for '{}'", query);
+    for (pkg, problem) in &issues {
+        println!("  {} {}", pkg.bold(), problem);
+    }
+    if !issues.is_empty() {
+        anyhow::bail!(error::Error::Verification(format!(
+            "{} problems in repo metadata",
+            issues.len()
+        )));
+    }
+    println!(
+        "{} Repo metadata OK ({} packages)",
+        "==>".green().bold(),
+        db.packages.len()
+    );
+    Ok(())
+}
+
+/// Walk through every `.scarabnew` file: show how it differs from the
+/// installed config, then keep the current file, replace it, or merge the
+/// two with `$MERGE` (default `vimdiff`)
+fn merge_configs(cfg: &config::Config, color_diff: bool) -> Result<()> {
+    let mut pending = Vec::new();
+    for root in cfg.roots() {
+        pending.extend(configs::pending(root)?.into_iter().map(|path| (root, path)));
+    }
+    if pending.is_empty() {
+        println!("{} No config files to merge", "==>".green().bold());
         return Ok(());
     }
 
-    for pkg in results {
-        let status = if db.get_installed(&pkg.name).is_some() {
-            "*".green().to_string()
+    let tool = std::env::var("MERGE").unwrap_or_else(|_| "vimdiff".to_string());
+    for (root, path) in pending {
+        let current = root.join(&path);
+        let new = configs::new_path(root, &path);
+        // Files under a repo's own root are shown with it
+        let path = if root == cfg.root {
+            format!("/{}", path)
         } else {
-            " ".to_string()
+            current.display().to_string()
         };
-        println!(
-            "{} {}/{} {} - {}",
-            status,
-            pkg.category.dimmed(),
-            pkg.name.bold(),
-            pkg.version,
-            pkg.description
-        );
+        println!("{} {}", "==>".green().bold(), path.bold());
+        show_config_diff(&current, &new, color_diff)?;
+
+        loop {
+            // End of input skips the rest, leaving each .scarabnew in place
+            let answer = prompt("==> [k]eep current, [r]eplace with new, [m]erge, [s]kip: ")?
+                .unwrap_or_default();
+            match answer.to_lowercase().as_str() {
+                "k" | "keep" => {
+                    std::fs::remove_file(&new)?;
+                    println!("  -> Kept {}", path);
+                }
+                "r" | "replace" => {
+                    std::fs::rename(&new, &current)?;
+                    println!("  -> Replaced {}", path);
+                }
+                "m" | "merge" => {
+                    let status = std::process::Command::new(&tool)
+                        .arg(&current)
+                        .arg(&new)
+                        .status()
+                        .with_context(|| format!("Failed to run {}", tool))?;
+                    if !status.success() {
+                        eprintln!("{} {} exited with {}", "warning:".yellow().bold(), tool, status);
+                    }
+                    // Ask again, so the merged result can be kept
+                    continue;
+                }
+                "" | "s" | "skip" => println!("  -> Skipped {}", path),
+                other => {
+                    println!("Unknown choice '{}'", other);
+                    continue;
+                }
+            }
+            break;
+        }
     }
     Ok(())
 }
 
-fn list_packages(cfg: &config::Config) -> Result<()> {
+/// Show how a `.scarabnew` file differs from the current one, through
+/// `diff -u` unless `builtin` is set or there's no `diff` to run
+fn show_config_diff(current: &std::path::Path, new: &std::path::Path, builtin: bool) -> Result<()> {
+    if !builtin {
+        match std::process::Command::new("diff").arg("-u").arg(current).arg(new).status() {
+            Ok(_) => return Ok(()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e).context("Failed to run diff"),
+        }
+    }
+    let read = |path: &std::path::Path| {
+        std::fs::read(path)
+            .map(|data| String::from_utf8_lossy(&data).into_owned())
+            .with_context(|| format!("Failed to read {}", path.display()))
+    };
+    print!(
+        "{}",
+        diff::unified(
+            &current.display().to_string(),
+            &new.display().to_string(),
+            &read(current)?,
+            &read(new)?
+        )
+    );
+    Ok(())
+}
+
+/// Reinstall `names`, or with `recursive` their whole installed dependency
+/// closure too, dependencies first
+fn reinstall(cfg: &config::Config, names: &[String], recursive: bool) -> Result<()> {
     let db = db::Database::load(cfg)?;
-    let installed = db.list_installed();
+    let mut order = Vec::new();
+    for name in names {
+        let installed = db
+            .get_installed(name)
+            .ok_or_else(|| error::Error::NotFound(format!("{} is not installed", name)))?;
+        if recursive {
+            let pkg = match db.find_version(name, &installed.version) {
+                Some(pkg) => pkg.clone(),
+                None => db.find_package(name)?,
+            };
+            order.extend(
+                db.dependency_closure(&pkg)
+                    .into_iter()
+                    .filter(|dep| dep.installed.is_some())
+                    .map(|dep| dep.name),
+            );
+        }
+        order.push(name.clone());
+    }
+    let mut seen = BTreeSet::new();
+    order.retain(|name| seen.insert(name.clone()));
 
-    if installed.is_empty() {
-        println!("No packages installed");
-        return Ok(());
+    let names: Vec<_> = order.iter().map(String::as_str).collect();
+    reinstall_packages(cfg, &db, &names, |names| {
+        error::Error::NotFound(format!("Could not reinstall: {}", names))
+    })
+}
+
+/// Reinstall the installed version of each package, in order, to restore its
+/// files. Packages whose version the repo no longer has are skipped, then
+/// reported through `unfixable`, so each caller picks the error kind.
+fn reinstall_packages(
+    cfg: &config::Config,
+    db: &db::Database,
+    names: &[&str],
+    unfixable: impl Fn(String) -> error::Error,
+) -> Result<()> {
+    let mut skipped = Vec::new();
+    for (i, name) in names.iter().enumerate() {
+        let Some(installed) = db.get_installed(name) else { continue };
+        match db.find_version(name, &installed.version) {
+            Some(pkg) => {
+                let step = (i + 1, names.len());
+                install_resolved(cfg, pkg, step, false, None, &InstallOptions::default())?
+            }
+            None => {
+                eprintln!(
+                    "{} {} {} is no longer in the repo, cannot reinstall",
+                    "warning:".yellow().bold(),
+                    name,
+                    installed.version
+                );
+                skipped.push(*name);
+            }
+        }
     }
 
-    for pkg in installed {
-        println!("{:<20} {:<12} {}", pkg.name.bold(), pkg.version, pkg.installed_at);
+    if !skipped.is_empty() {
+        anyhow::bail!(unfixable(skipped.join(", ")));
     }
+    println!("{} Reinstalled {} packages", "==>".green().bold(), names.len());
     Ok(())
 }
 
-fn show_info(cfg: &config::Config, name: &str) -> Result<()> {
-    let db = db::Database::load(cfg)?;
-    let pkg = db.find_package(name)?;
+fn run_doctor(cfg: &config::Config) -> Result<()> {
+    println!("{} Checking scarab health...", "==>".green().bold());
+    let checks = doctor::run(cfg);
+    for check in &checks {
+        let label = match check.status {
+            doctor::Status::Pass => " ok ".green(),
+            doctor::Status::Warn => "warn".yellow(),
+            doctor::Status::Fail => "FAIL".red().bold(),
+        };
+        println!("  [{}] {:<13} {}", label, check.name, check.detail);
+    }
 
-    println!("{:<14} {}", "Name:".bold(), pkg.name);
-    println!("{:<14} {}", "Version:".bold(), pkg.version);
-    println!("{:<14} {}", "Category:".bold(), pkg.category);
-    println!("{:<14} {}", "Description:".bold(), pkg.description);
+    let count = |status| checks.iter().filter(|c| c.status == status).count();
+    let failed = count(doctor::Status::Fail);
     println!(
-        "{:<14} {}",
-        "Depends:".bold(),
-        if pkg.depends.is_empty() {
-            "none".to_string()
-        } else {
-            pkg.depends.join(", ")
-        }
+        "{} {} passed, {} warnings, {} failed",
+        "==>".green().bold(),
+        count(doctor::Status::Pass),
+        count(doctor::Status::Warn),
+        failed
     );
-    println!("{:<14} {}", "Size:".bold(), pkg.size);
+    if failed > 0 {
+        anyhow::bail!("{} health checks failed", failed);
+    }
+    Ok(())
+}
 
-    if let Some(installed) = db.get_installed(name) {
+fn run_mirror_status(cfg: &config::Config) -> Result<()> {
+    println!("{} Probing repos...", "==>".green().bold());
+    let repos = cfg.repos();
+    let results: Vec<Result<fetch::Probe>> = std::thread::scope(|scope| {
+        let handles: Vec<_> = repos
+            .iter()
+            .map(|repo| scope.spawn(move || fetch::probe_repo(cfg, repo)))
+            .collect();
+        handles
+            .into_iter()
+            .map(|h| h.join().unwrap_or_else(|_| Err(anyhow::anyhow!("probe thread panicked"))))
+            .collect()
+    });
+
+    let now = timestamp::now_secs();
+    let (mut ok, mut stale, mut down) = (0, 0, 0);
+    for (repo, result) in repos.iter().zip(results) {
+        let probe = match result {
+            Ok(probe) => probe,
+            Err(e) => {
+                down += 1;
+                println!("  [{}] {:<12} {:#}", "down ".red().bold(), repo.name, e);
+                continue;
+            }
+        };
+        let age = probe.modified.map(|modified| now.saturating_sub(modified));
+        let is_stale = cfg.stale_days > 0 && age.is_some_and(|age| age >= cfg.stale_days * 86400);
+        let label = if is_stale {
+            stale += 1;
+            "stale".yellow()
+        } else {
+            ok += 1;
+            " ok  ".green()
+        };
+        let updated = match age {
+            Some(age) => format!("updated {} ago", timestamp::format_age(age)),
+            None => "update time unknown".to_string(),
+        };
         println!(
-            "{:<14} {} ({})",
-            "Status:".bold(),
-            "installed".green(),
-            installed.version
+            "  [{}] {:<12} {:>5}ms  {:<18} {}",
+            label,
+            repo.name,
+            probe.latency.as_millis(),
+            updated,
+            probe.url
         );
-    } else {
-        println!("{:<14} {}", "Status:".bold(), "not installed".yellow());
     }
 
+    println!("{} {} ok, {} stale, {} down", "==>".green().bold(), ok, stale, down);
+    if down > 0 {
+        anyhow::bail!(error::Error::Network(format!("{} repos unreachable", down)));
+    }
     Ok(())
 }
 
-fn sync_db(cfg: &config::Config) -> Result<()> {
-    println!("{} Syncing package database...", "==>".green().bold());
-    fetch::sync_repo_db(cfg)?;
-    println!("{} Database synced", "==>".green().bold());
-    Ok(())
-}
-
-fn upgrade_packages(cfg: &config::Config) -> Result<()> {
+/// Print the packages asked for by name (or, with `all`, every installed
+/// package) so `install --from-file` can recreate the set elsewhere
+fn export_installed(cfg: &config::Config, all: bool, versions: bool, json: bool) -> Result<()> {
     let db = db::Database::load(cfg)?;
-    let upgrades = db.check_upgrades();
+    let packages: Vec<_> = db
+        .list_installed()
+        .into_iter()
+        .filter(|pkg| all || !pkg.dependency)
+        .collect();
 
-    if upgrades.is_empty() {
-        println!("{} System is up to date", "==>".green().bold());
+    if json {
+        let entries: Vec<_> = packages
+            .iter()
+            .map(|pkg| {
+                if versions {
+                    serde_json::json!({ "name": pkg.name, "version": pkg.version })
+                } else {
+                    serde_json::json!({ "name": pkg.name })
+                }
+            })
+            .collect();
+        println!("{}", serde_json::to_string_pretty(&entries)?);
         return Ok(());
     }
 
-    for (name, old_ver, new_ver) in &upgrades {
-        println!("  {} {} -> {}", name.bold(), old_ver.dimmed(), new_ver.green());
+    println!("# scarab export, {} ({} packages)", timestamp::now(), packages.len());
+    for pkg in packages {
+        if versions {
+            println!("{}={}", pkg.name, pkg.version);
+        } else {
+            println!("{}", pkg.name);
+        }
+    }
+    Ok(())
+}
+
+fn show_history(
+    cfg: &config::Config,
+    failures: bool,
+    limit: Option<usize>,
+    pager: bool,
+) -> Result<()> {
+    let mut entries = history::load(cfg)?;
+    if failures {
+        entries.retain(|t| t.failure.is_some());
+    }
+    if let Some(limit) = limit {
+        entries.drain(..entries.len().saturating_sub(limit));
     }
 
-    for (name, _, _) in &upgrades {
-        install_package(cfg, name, true)?;
+    let mut out = String::new();
+    for entry in &entries {
+        match &entry.failure {
+            Some(failure) => writeln!(
+                out,
+                "{} {} {} {}",
+                "==>".green().bold(),
+                entry.time.dimmed(),
+                entry.command.bold(),
+                format!("FAILED ({})", failure.phase.as_deref().unwrap_or("unknown")).red()
+            )?,
+            None => writeln!(
+                out,
+                "{} {} {}",
+                "==>".green().bold(),
+                entry.time.dimmed(),
+                entry.command.bold()
+            )?,
+        }
+        for change in &entry.changes {
+            writeln!(out, "    {}", change)?;
+        }
+        if let Some(failure) = &entry.failure {
+            writeln!(out, "    {}", failure.reason)?;
+        }
     }
+    if entries.is_empty() {
+        println!("No transactions recorded");
+        return Ok(());
+    }
+    pager::page(&out, pager)
+}
 
+fn manage_keys(cfg: &config::Config, action: KeyAction) -> Result<()> {
+    match action {
+        KeyAction::Add { file } => {
+            let key = keyring::add_key(cfg, &file)?;
+            println!("{} Added key {}", "==>".green().bold(), key.id_hex().bold());
+        }
+        KeyAction::List => {
+            let keys = keyring::list_keys(cfg)?;
+            if keys.is_empty() {
+                println!("No keys in keyring");
+            }
+            for key in keys {
+                println!("{} {} {}", key.id_hex().bold(), key.encoded(), key.comment.dimmed());
+            }
+        }
+        KeyAction::Remove { id } => {
+            keyring::remove_key(cfg, &id)?;
+            println!("{} Removed key {}", "==>".green().bold(), id.bold());
+        }
+    }
     Ok(())
 }
 
-fn build_package(cfg: &config::Config, name: &str) -> Result<()> {
+fn build_package(
+    cfg: &config::Config,
+    name: &str,
+    compression: package::Compression,
+    output_dir: &std::path::Path,
+) -> Result<()> {
     println!(
         "{} Building {} from Portfile...",
         "==>".green().bold(),
@@ -270,7 +3175,57 @@ fn build_package(cfg: &config::Config, name: &str) -> Result<()> {
 
     // Find Portfile
     let portfile = package::find_portfile(&cfg.ports_dir, name)?;
-    package::build_from_portfile(&portfile, cfg)?;
+    for tarball in package::build_from_portfile(&portfile, cfg, compression, output_dir)? {
+        println!("{} Built {}", "==>".green().bold(), tarball.display());
+    }
+
+    Ok(())
+}
+
+/// Validate a Portfile's metadata, source and patches without building
+fn dry_run_build(cfg: &config::Config, name: &str) -> Result<()> {
+    println!(
+        "{} Checking {} Portfile (dry run)...",
+        "==>".green().bold(),
+        name.bold()
+    );
+    let portfile = package::find_portfile(&cfg.ports_dir, name)?;
+    let dry = package::dry_run_portfile(&portfile, cfg)?;
+    let port = &dry.port;
+
+    println!("{:<14} {}", "Name:".bold(), port.name);
+    println!("{:<14} {}", "Version:".bold(), port.version);
+    if !port.packages.is_empty() {
+        println!("{:<14} {}", "Packages:".bold(), port.packages.join(" "));
+    }
+    println!(
+        "{:<14} {}",
+        "Source:".bold(),
+        if port.source.is_empty() { "none" } else { &port.source }
+    );
+    println!(
+        "{:<14} {}",
+        "Patches:".bold(),
+        if dry.patches.is_empty() { "none".to_string() } else { dry.patches.join(" ") }
+    );
+
+    // Dependencies as they would resolve on this system right now
+    let db = db::Database::load(cfg)?;
+    let depends = db.active_depends(&port.depends);
+    if depends.is_empty() {
+        println!("{:<14} none", "Depends:".bold());
+    } else {
+        println!("{}", "Depends:".bold());
+        for dep in &depends {
+            let status = match (db.get_installed(dep), db.find_package(dep)) {
+                (Some(installed), _) => format!("{} installed", installed.version).green(),
+                (None, Ok(pkg)) => format!("{} in repo '{}'", pkg.version, pkg.repo).yellow(),
+                (None, Err(_)) => "missing".red(),
+            };
+            println!("  {:<20} {}", dep, status);
+        }
+    }
 
+    println!("{} Source and patches OK", "==>".green().bold());
     Ok(())
 }
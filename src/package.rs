@@ -1,63 +1,503 @@
 pub use crate::db::PackageInfo;
 use crate::config::Config;
 use crate::db::InstalledPackage;
+use crate::error::Error;
 use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BTreeSet, HashSet};
 use std::fs;
+use std::io::{Read, Write};
 use std::path::{Path, PathBuf};
 use std::process::Command;
 
-/// Extract a package tarball to the root filesystem
-pub fn extract_package(tarball: &Path, root: &Path) -> Result<()> {
+/// Name of the metadata member embedded in built packages
+pub const PKGINFO: &str = ".PKGINFO";
+
+/// Package metadata embedded in a tarball's `.PKGINFO` member
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PkgMeta {
+    pub name: String,
+    pub version: String,
+    #[serde(default)]
+    pub arch: String,
+    #[serde(default)]
+    pub category: String,
+    #[serde(default)]
+    pub description: String,
+    #[serde(default)]
+    pub depends: Vec<String>,
+    /// SHA256 of every regular file, keyed by path relative to root
+    #[serde(default)]
+    pub files: BTreeMap<String, String>,
+}
+
+/// Metadata variables read from a Portfile
+#[derive(Debug, Clone)]
+pub struct PortInfo {
+    pub name: String,
+    pub version: String,
+    pub description: String,
+    pub depends: Vec<String>,
+    /// Source tarball URL (empty if the port has none)
+    pub source: String,
+    /// Split packages built from this port, each staged by its own
+    /// `package_<name>()` (empty for a single package staged by `build()`)
+    pub packages: Vec<String>,
+}
+
+/// What `build --dry-run` found out about a port without building it
+pub struct DryRun {
+    pub port: PortInfo,
+    /// Patch file names, in the order they apply
+    pub patches: Vec<String>,
+}
+
+/// Output compression for built packages
+#[derive(Debug, Clone, Copy, clap::ValueEnum)]
+pub enum Compression {
+    Zstd,
+    Gzip,
+    Xz,
+}
+
+impl Compression {
+    fn extension(self) -> &'static str {
+        match self {
+            Compression::Zstd => "tar.zst",
+            Compression::Gzip => "tar.gz",
+            Compression::Xz => "tar.xz",
+        }
+    }
+}
+
+/// Whether a path looks like a package tarball we know how to extract
+pub fn is_package_file(path: &Path) -> bool {
+    let name = path.to_string_lossy();
+    [".tar.zst", ".tar.gz", ".tgz", ".tar.xz"]
+        .iter()
+        .any(|ext| name.ends_with(ext))
+}
+
+/// Extract a package tarball to the root filesystem, returning the paths
+/// (relative to root) of the entries it unpacked, directories with a trailing
+/// `/`. If extraction fails part way, or the result doesn't match the archive
+/// (or its `.PKGINFO` file list), the files and directories this extraction
+/// created are removed again and the install fails.
+pub fn extract_package(tarball: &Path, root: &Path) -> Result<Vec<String>> {
     eprintln!("  -> Extracting to {}...", root.display());
+    let _spinner = crate::progress::Spinner::start("Extracting...");
 
     let file = fs::File::open(tarball)?;
+    let declared = read_pkginfo(tarball).ok();
 
     // Detect compression from filename
     let filename = tarball.to_string_lossy();
+    let failed =
+        |e: anyhow::Error| Error::Extraction(format!("Failed to extract {}: {:#}", filename, e));
 
-    if filename.ends_with(".tar.zst") {
-        let decoder = zstd::Decoder::new(file)?;
-        let mut archive = tar::Archive::new(decoder);
-        archive.set_preserve_permissions(true);
-        archive.unpack(root)?;
+    let unpacked = if filename.ends_with(".tar.zst") {
+        let decoder = zstd::Decoder::new(file).map_err(|e| failed(e.into()))?;
+        unpack_archive(tar::Archive::new(decoder), root).map_err(failed)?
     } else if filename.ends_with(".tar.gz") || filename.ends_with(".tgz") {
         let decoder = flate2::read::GzDecoder::new(file);
-        let mut archive = tar::Archive::new(decoder);
-        archive.set_preserve_permissions(true);
-        archive.unpack(root)?;
+        unpack_archive(tar::Archive::new(decoder), root).map_err(failed)?
     } else if filename.ends_with(".tar.xz") {
         // Use xz command
+        let output = Command::new("tar")
+            .args(["tJf", &tarball.to_string_lossy()])
+            .output()
+            .map_err(|e| failed(e.into()))?;
+        if !output.status.success() {
+            bail!(Error::Extraction(format!("Failed to list {}", filename)));
+        }
+        let files: Vec<String> = String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .map(|l| l.trim_start_matches("./").to_string())
+            .filter(|l| !l.is_empty() && l != PKGINFO)
+            .collect();
+        let created: Vec<_> = files
+            .iter()
+            .flat_map(|f| missing_paths(root, &root.join(f)))
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .collect();
+
         let status = Command::new("tar")
             .args(["xJf", &tarball.to_string_lossy(), "-C", &root.to_string_lossy()])
-            .status()?;
-        if !status.success() {
-            bail!("Failed to extract {}", filename);
+            .arg(format!("--exclude={}", PKGINFO))
+            .status();
+        if !status.as_ref().is_ok_and(|s| s.success()) {
+            roll_back(&created);
+            bail!(Error::Extraction(format!("Failed to extract {}", filename)));
+        }
+
+        Unpacked {
+            files,
+            created,
+            skipped: Vec::new(),
         }
     } else {
-        bail!("Unknown archive format: {}", filename);
+        bail!(Error::Extraction(format!("Unknown archive format: {}", filename)));
+    };
+
+    check_unpacked(root, &unpacked, declared.as_ref())?;
+    Ok(unpacked.files)
+}
+
+/// Flush extracted `files` (relative to `root`) and the directories holding
+/// them to disk, so they survive a crash once the install is recorded
+pub fn sync_files(root: &Path, files: &[String]) -> Result<()> {
+    let mut dirs = BTreeSet::new();
+    for file in files {
+        let path = root.join(file.trim_end_matches('/'));
+        let Ok(meta) = fs::symlink_metadata(&path) else { continue };
+        if meta.is_dir() {
+            dirs.insert(path.clone());
+        } else if meta.is_file() {
+            fs::File::open(&path)
+                .and_then(|f| f.sync_all())
+                .with_context(|| format!("Failed to sync {}", path.display()))?;
+        }
+        // Every ancestor up to root holds a new entry
+        let mut parent = path.parent();
+        while let Some(dir) = parent.filter(|d| d.starts_with(root)) {
+            if !dirs.insert(dir.to_path_buf()) {
+                break;
+            }
+            parent = dir.parent();
+        }
     }
+    for dir in dirs {
+        fs::File::open(&dir)
+            .and_then(|d| d.sync_all())
+            .with_context(|| format!("Failed to sync {}", dir.display()))?;
+    }
+    Ok(())
+}
 
+/// What an extraction wrote, for checking it against the archive
+struct Unpacked {
+    /// Entries relative to root, directories with a trailing `/` so removal
+    /// can clean up the ones left empty
+    files: Vec<String>,
+    /// Paths, directories included, that didn't exist before, i.e. what a
+    /// rollback may remove
+    created: Vec<PathBuf>,
+    /// Entries the tar crate refused to unpack (e.g. escaping the root)
+    skipped: Vec<String>,
+}
+
+/// Unpack every entry except the embedded metadata. Directories are applied
+/// last (like `Archive::unpack`) so read-only modes don't block their contents.
+/// On an error part way through (e.g. a corrupt stream), whatever was created
+/// so far is removed again.
+fn unpack_archive<R: Read>(archive: tar::Archive<R>, root: &Path) -> Result<Unpacked> {
+    let mut unpacked = Unpacked {
+        files: Vec::new(),
+        created: Vec::new(),
+        skipped: Vec::new(),
+    };
+    if let Err(e) = unpack_entries(archive, root, &mut unpacked) {
+        roll_back(&unpacked.created);
+        return Err(e);
+    }
+    Ok(unpacked)
+}
+
+fn unpack_entries<R: Read>(
+    mut archive: tar::Archive<R>,
+    root: &Path,
+    unpacked: &mut Unpacked,
+) -> Result<()> {
+    archive.set_preserve_permissions(true);
+    unpacked.created.extend(missing_paths(Path::new("/"), root));
+    fs::create_dir_all(root)?;
+
+    let mut directories = Vec::new();
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let path = entry.path()?.into_owned();
+        if is_pkginfo(&path) {
+            continue;
+        }
+        if entry.header().entry_type().is_dir() {
+            directories.push(entry);
+            continue;
+        }
+
+        let rel = path.strip_prefix(".").unwrap_or(&path).to_string_lossy().into_owned();
+        // Recorded first, so a failure half way through still rolls them back
+        unpacked.created.extend(missing_paths(root, &root.join(&rel)));
+        if !entry.unpack_in(root)? {
+            unpacked.skipped.push(rel);
+            continue;
+        }
+        unpacked.files.push(rel);
+    }
+    for mut dir in directories {
+        let path = dir.path()?.into_owned();
+        let rel = path.strip_prefix(".").unwrap_or(&path).to_string_lossy();
+        let rel = rel.trim_end_matches('/');
+        unpacked.created.extend(missing_paths(root, &root.join(rel)));
+        dir.unpack_in(root)?;
+        if !rel.is_empty() {
+            unpacked.files.push(format!("{}/", rel));
+        }
+    }
     Ok(())
 }
 
-/// Remove files belonging to a package
-pub fn remove_package_files(cfg: &Config, pkg: &InstalledPackage) -> Result<()> {
+/// `path` and those of its ancestors below `root` that don't exist yet,
+/// outermost first
+fn missing_paths(root: &Path, path: &Path) -> Vec<PathBuf> {
+    let mut missing = Vec::new();
+    let mut next = Some(path);
+    while let Some(p) = next.filter(|p| p.starts_with(root) && *p != root) {
+        if fs::symlink_metadata(p).is_ok() {
+            break;
+        }
+        missing.push(p.to_path_buf());
+        next = p.parent();
+    }
+    missing.reverse();
+    missing
+}
+
+/// Remove what a failed extraction created, deepest first. Directories only
+/// go once they're empty, so anything else put there meanwhile survives.
+fn roll_back(created: &[PathBuf]) {
+    let mut created: Vec<_> = created.iter().collect();
+    created.sort_by_key(|p| Reverse(p.components().count()));
+    for path in created {
+        if fs::symlink_metadata(path).is_ok_and(|m| m.is_dir()) {
+            fs::remove_dir(path).ok();
+        } else {
+            fs::remove_file(path).ok();
+        }
+    }
+}
+
+/// Make sure every entry landed on disk and every file `.PKGINFO` declares was
+/// in the archive, rolling back newly created files if not
+fn check_unpacked(root: &Path, unpacked: &Unpacked, declared: Option<&PkgMeta>) -> Result<()> {
+    let mut problems: Vec<String> = unpacked
+        .skipped
+        .iter()
+        .map(|f| format!("{} was not unpacked", f))
+        .collect();
+    for file in &unpacked.files {
+        if fs::symlink_metadata(root.join(file)).is_err() {
+            problems.push(format!("{} is missing after extraction", file));
+        }
+    }
+    if let Some(meta) = declared {
+        let written: HashSet<&str> = unpacked.files.iter().map(|f| f.as_str()).collect();
+        for path in meta.files.keys() {
+            if !written.contains(path.as_str()) {
+                problems.push(format!("{} is listed in {} but not in the archive", path, PKGINFO));
+            }
+        }
+    }
+    if problems.is_empty() {
+        return Ok(());
+    }
+
+    roll_back(&unpacked.created);
+    for problem in &problems {
+        eprintln!("  -> {}", problem);
+    }
+    bail!(Error::Verification(format!(
+        "Incomplete extraction: {} problems, rolled back",
+        problems.len()
+    )))
+}
+
+fn is_pkginfo(path: &Path) -> bool {
+    path.strip_prefix(".").unwrap_or(path) == Path::new(PKGINFO)
+}
+
+/// One entry of a package archive, as `inspect` reports it
+pub struct Member {
+    /// Path relative to root
+    pub path: String,
+    pub size: u64,
+    /// Content hash, for regular files
+    pub sha256: Option<String>,
+    pub is_dir: bool,
+}
+
+/// Every entry of a package tarball except the embedded metadata, read
+/// without unpacking anything
+pub fn list_members(tarball: &Path) -> Result<Vec<Member>> {
+    let filename = tarball.to_string_lossy();
+    let file = fs::File::open(tarball)
+        .with_context(|| format!("Failed to open {}", tarball.display()))?;
+    let unreadable =
+        |e: anyhow::Error| Error::Extraction(format!("Failed to read {}: {:#}", filename, e));
+
+    if filename.ends_with(".tar.zst") {
+        let decoder = zstd::Decoder::new(file).map_err(|e| unreadable(e.into()))?;
+        Ok(read_members(tar::Archive::new(decoder)).map_err(unreadable)?)
+    } else if filename.ends_with(".tar.gz") || filename.ends_with(".tgz") {
+        let decoder = flate2::read::GzDecoder::new(file);
+        Ok(read_members(tar::Archive::new(decoder)).map_err(unreadable)?)
+    } else if filename.ends_with(".tar.xz") {
+        let mut child = Command::new("xz")
+            .arg("-dc")
+            .stdin(file)
+            .stdout(std::process::Stdio::piped())
+            .spawn()
+            .context("Failed to run xz")?;
+        let members = read_members(tar::Archive::new(child.stdout.take().unwrap()));
+        if !child.wait()?.success() {
+            bail!(Error::Extraction(format!("Failed to decompress {}", filename)));
+        }
+        members
+    } else {
+        bail!(Error::Extraction(format!("Unknown archive format: {}", filename)));
+    }
+}
+
+fn read_members<R: Read>(mut archive: tar::Archive<R>) -> Result<Vec<Member>> {
+    use sha2::{Digest, Sha256};
+
+    let mut members = Vec::new();
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let path = entry.path()?.into_owned();
+        let rel = path.strip_prefix(".").unwrap_or(&path).to_string_lossy().into_owned();
+        if is_pkginfo(&path) || rel.is_empty() {
+            continue;
+        }
+        let kind = entry.header().entry_type();
+        let sha256 = if kind.is_file() {
+            let mut hasher = Sha256::new();
+            std::io::copy(&mut entry, &mut hasher)?;
+            Some(hex::encode(hasher.finalize()))
+        } else {
+            None
+        };
+        members.push(Member {
+            path: rel.trim_end_matches('/').to_string(),
+            size: entry.size(),
+            sha256,
+            is_dir: kind.is_dir(),
+        });
+    }
+    Ok(members)
+}
+
+/// Read the `.PKGINFO` metadata embedded in a package tarball
+pub fn read_pkginfo(tarball: &Path) -> Result<PkgMeta> {
+    let filename = tarball.to_string_lossy();
+    let file = fs::File::open(tarball)
+        .with_context(|| format!("Failed to open {}", tarball.display()))?;
+    let unreadable =
+        |e: anyhow::Error| Error::Extraction(format!("Failed to read {}: {:#}", filename, e));
+
+    let content = if filename.ends_with(".tar.zst") {
+        let decoder = zstd::Decoder::new(file).map_err(|e| unreadable(e.into()))?;
+        read_member(tar::Archive::new(decoder)).map_err(unreadable)?
+    } else if filename.ends_with(".tar.gz") || filename.ends_with(".tgz") {
+        let decoder = flate2::read::GzDecoder::new(file);
+        read_member(tar::Archive::new(decoder)).map_err(unreadable)?
+    } else if filename.ends_with(".tar.xz") {
+        let output = Command::new("tar")
+            .args(["xJOf", &filename, PKGINFO])
+            .output()?;
+        output.status.success().then_some(output.stdout)
+    } else {
+        bail!(Error::Extraction(format!("Unknown archive format: {}", filename)));
+    };
+
+    let content = content.with_context(|| format!("{} has no {} metadata", filename, PKGINFO))?;
+    serde_json::from_slice(&content).with_context(|| format!("Invalid {} in {}", PKGINFO, filename))
+}
+
+fn read_member<R: Read>(mut archive: tar::Archive<R>) -> Result<Option<Vec<u8>>> {
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        if is_pkginfo(&entry.path()?) {
+            let mut content = Vec::new();
+            entry.read_to_end(&mut content)?;
+            return Ok(Some(content));
+        }
+    }
+    Ok(None)
+}
+
+/// What removing a package's files did
+#[derive(Default)]
+pub struct Removal {
+    /// Files deleted (emptied directories aren't counted)
+    pub removed: usize,
+    /// Paths that couldn't be deleted, with the reason
+    pub failed: Vec<(PathBuf, std::io::Error)>,
+    /// Recorded files that were already gone, i.e. removed behind scarab's back
+    pub missing: Vec<PathBuf>,
+}
+
+/// Remove files belonging to a package. Paths that another installed package
+/// also owns (`shared`) are kept, and directories are only removed once they
+/// are genuinely empty, deepest first.
+pub fn remove_package_files(
+    cfg: &Config,
+    pkg: &InstalledPackage,
+    shared: &HashSet<String>,
+) -> Result<Removal> {
+    let root = pkg.root(cfg);
+    let mut dirs = BTreeSet::new();
+    let mut removal = Removal::default();
+
     for file in &pkg.files {
-        let path = cfg.root.join(file);
-        if path.is_file() {
-            fs::remove_file(&path).ok();
+        let rel = file.trim_end_matches('/');
+        let path = root.join(rel);
+
+        if !shared.contains(rel) {
+            match fs::symlink_metadata(&path) {
+                Ok(meta) if meta.is_dir() => {
+                    dirs.insert(path.clone());
+                }
+                Ok(_) => match fs::remove_file(&path) {
+                    Ok(()) => removal.removed += 1,
+                    Err(e) => removal.failed.push((path.clone(), e)),
+                },
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                    removal.missing.push(path.clone());
+                }
+                Err(e) => removal.failed.push((path.clone(), e)),
+            }
+        }
+
+        // Every ancestor below root is a cleanup candidate
+        let mut parent = path.parent();
+        while let Some(dir) = parent {
+            if dir == root || !dir.starts_with(root) {
+                break;
+            }
+            dirs.insert(dir.to_path_buf());
+            parent = dir.parent();
         }
     }
 
-    // Clean empty directories (reverse order)
-    for file in pkg.files.iter().rev() {
-        let path = cfg.root.join(file);
-        if let Some(parent) = path.parent() {
-            fs::remove_dir(parent).ok(); // Only removes if empty
+    let mut dirs: Vec<_> = dirs.into_iter().collect();
+    dirs.sort_by_key(|d| Reverse(d.components().count()));
+
+    for dir in dirs {
+        let rel = dir.strip_prefix(root).unwrap_or(&dir);
+        if shared.contains(rel.to_string_lossy().as_ref()) {
+            continue;
+        }
+        let empty = fs::read_dir(&dir)
+            .map(|mut entries| entries.next().is_none())
+            .unwrap_or(false);
+        if empty && let Err(e) = fs::remove_dir(&dir) {
+            removal.failed.push((dir, e));
         }
     }
 
-    Ok(())
+    Ok(removal)
 }
 
 /// Find a Portfile for a package
@@ -68,31 +508,115 @@ pub fn find_portfile(ports_dir: &Path, name: &str) -> Result<PathBuf> {
             return Ok(portfile);
         }
     }
-    bail!(
+    bail!(Error::NotFound(format!(
         "Portfile not found for '{}' in {}",
         name,
         ports_dir.display()
-    )
+    )))
 }
 
-/// Build a package from its Portfile (shell-based, delegates to sh)
-pub fn build_from_portfile(portfile: &Path, cfg: &Config) -> Result<()> {
-    let port_dir = portfile
-        .parent()
-        .context("Invalid Portfile path")?;
-    let name = port_dir
-        .file_name()
-        .context("Invalid port directory")?
-        .to_string_lossy();
+/// Mirrors a `mirror://<alias>/` source can name without configuring them
+const MIRRORS: &[(&str, &str)] = &[
+    ("gnu", "https://ftpmirror.gnu.org"),
+    ("kernel", "https://cdn.kernel.org/pub"),
+    ("savannah", "https://download.savannah.gnu.org/releases"),
+    ("sourceforge", "https://downloads.sourceforge.net"),
+];
 
-    let work_dir = cfg.cache_dir.join("work").join(name.as_ref());
-    let pkg_dir = cfg.cache_dir.join("pkg").join(name.as_ref());
+/// Expand a Portfile `source` the same way for metadata and the build:
+/// `${name}` and `${version}`, then a `mirror://<alias>/` prefix from the
+/// config's `mirrors` or the built-in ones
+fn expand_source(source: &str, name: &str, version: &str, cfg: &Config) -> Result<String> {
+    let mut url = source.replace("${name}", name).replace("${version}", version);
+    if let Some(rest) = url.strip_prefix("mirror://") {
+        let (alias, path) = rest.split_once('/').unwrap_or((rest, ""));
+        let base = cfg
+            .mirrors
+            .get(alias)
+            .map(String::as_str)
+            .or_else(|| MIRRORS.iter().find(|(a, _)| *a == alias).map(|(_, base)| *base))
+            .with_context(|| format!("Unknown mirror '{}' in source {}", alias, source))?;
+        url = format!("{}/{}", base.trim_end_matches('/'), path);
+    }
+    if let Some(start) = url.find("${") {
+        let var = url[start..].split_inclusive('}').next().unwrap_or_default();
+        bail!(Error::Build(format!(
+            "Unsupported variable {} in source {} (only ${{name}} and ${{version}})",
+            var,
+            source
+        )));
+    }
+    Ok(url)
+}
 
-    fs::create_dir_all(&work_dir)?;
-    fs::create_dir_all(&pkg_dir)?;
+/// Read the metadata variables (`name`, `version`, `description`, `depends`,
+/// `source`, `packages`) by sourcing the Portfile in a subshell. `source`
+/// comes back expanded, exactly as the build will fetch it.
+pub fn read_portfile(portfile: &Path, cfg: &Config) -> Result<PortInfo> {
+    let output = Command::new("sh")
+        .arg("-c")
+        .arg(concat!(
+            r#". "$1" && printf '%s\n' "#,
+            r#""$name" "$version" "$description" "$depends" "$source" "$packages""#
+        ))
+        .arg("sh")
+        .arg(portfile)
+        .output()?;
+    if !output.status.success() {
+        bail!(Error::Build(format!("Failed to read {}", portfile.display())));
+    }
 
-    // Source the Portfile and run build()
-    let script = format!(
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let mut lines = stdout.lines().map(|l| l.trim().to_string());
+    let mut next = || lines.next().unwrap_or_default();
+    let (name, version, description, depends, source) = (next(), next(), next(), next(), next());
+    let packages = next();
+
+    // Fall back to the port directory name
+    let name = if name.is_empty() {
+        portfile
+            .parent()
+            .and_then(|d| d.file_name())
+            .context("Invalid Portfile path")?
+            .to_string_lossy()
+            .into_owned()
+    } else {
+        name
+    };
+    if version.is_empty() {
+        bail!(Error::Build(format!("{} does not set a version", portfile.display())));
+    }
+
+    let source = expand_source(&source, &name, &version, cfg)
+        .with_context(|| Error::Build(format!("Invalid source in {}", portfile.display())))?;
+
+    Ok(PortInfo {
+        name,
+        version,
+        description,
+        depends: depends.split_whitespace().map(String::from).collect(),
+        source,
+        packages: packages.split_whitespace().map(String::from).collect(),
+    })
+}
+
+/// Shell script that sources the Portfile, downloads `source` (the expanded
+/// URL from [`read_portfile`], overriding the Portfile's own) into `$SRC`
+/// (checking it against `sha256` when the Portfile sets one), applies the
+/// port's patches, and then runs `build()` unless `prepare_only`. A port
+/// that sets `packages` then has each one staged by `package_<name>()`
+/// (dashes become underscores) into `$PKG/<name>`; whatever that function
+/// leaves in `description` and `depends` goes to `$PKG/<name>.meta`.
+fn build_script(
+    portfile: &Path,
+    source: &str,
+    port_dir: &Path,
+    work_dir: &Path,
+    pkg_dir: &Path,
+    prepare_only: bool,
+) -> String {
+    let build = if prepare_only { "" } else { SPLIT_BUILD };
+    format!(
         r#"
 set -e
 PKG="{pkg_dir}"
@@ -101,12 +625,16 @@ MAKEFLAGS="-j$(nproc)"
 export PKG SRC MAKEFLAGS
 
 . "{portfile}"
+source='{source}'
 
 # Download source
 if [ -n "$source" ]; then
     cd "$SRC"
     file=$(basename "$source")
     [ -f "$file" ] || curl -fL -o "$file" "$source"
+    if [ -n "$sha256" ]; then
+        echo "$sha256  $file" | sha256sum -c --quiet - >/dev/null 2>&1 || {{ echo "Source checksum mismatch: $file" >&2; exit 1; }}
+    fi
     case "$file" in
         *.tar.gz|*.tgz) tar xzf "$file" ;;
         *.tar.xz) tar xJf "$file" ;;
@@ -123,24 +651,265 @@ if [ -d "{port_dir}/patches" ]; then
     done
 fi
 
-cd "$SRC"
-build
-"#,
+{build}"#,
         pkg_dir = pkg_dir.display(),
         work_dir = work_dir.display(),
         portfile = portfile.display(),
+        source = source.replace('\'', r"'\''"),
         port_dir = port_dir.display(),
-    );
+    )
+}
 
-    let status = Command::new("sh")
-        .arg("-c")
-        .arg(&script)
-        .status()?;
+/// The build step of [`build_script`], including split packaging
+const SPLIT_BUILD: &str = r#"cd "$SRC"
+build
+for sub in $packages; do
+    fn="package_$(echo "$sub" | tr - _)"
+    command -v "$fn" >/dev/null || { echo "No $fn() for split package $sub" >&2; exit 1; }
+    (
+        PKG="$PKG/$sub"
+        mkdir -p "$PKG"
+        cd "$SRC"
+        "$fn"
+        printf '%s\n' "$description" "$depends" > "$PKG.meta"
+    )
+done
+"#;
+
+/// Patch file names under the port's `patches/`, in the order they apply
+fn list_patches(port_dir: &Path) -> Result<Vec<String>> {
+    let dir = port_dir.join("patches");
+    if !dir.is_dir() {
+        return Ok(Vec::new());
+    }
+    let mut patches = Vec::new();
+    for entry in fs::read_dir(&dir)? {
+        let name = entry?.file_name().to_string_lossy().into_owned();
+        if name.ends_with(".patch") {
+            patches.push(name);
+        }
+    }
+    patches.sort();
+    Ok(patches)
+}
+
+/// Fetch and verify a port's source and apply its patches in a scratch
+/// directory, without running `build()` or touching the real work dir
+pub fn dry_run_portfile(portfile: &Path, cfg: &Config) -> Result<DryRun> {
+    let port_dir = portfile.parent().context("Invalid Portfile path")?;
+    let name = port_dir
+        .file_name()
+        .context("Invalid port directory")?
+        .to_string_lossy();
+    let port = read_portfile(portfile, cfg)?;
+
+    let scratch = cfg.cache_dir.join("work").join(format!("{}.dry-run", name));
+    if scratch.exists() {
+        fs::remove_dir_all(&scratch)?;
+    }
+    fs::create_dir_all(&scratch)?;
+
+    let script =
+        build_script(portfile, &port.source, port_dir, &scratch, &scratch.join("pkg"), true);
+    let status = crate::deadline::status(Command::new("sh").arg("-c").arg(&script));
+    fs::remove_dir_all(&scratch).ok();
+    if !status?.success() {
+        bail!(Error::Build(format!("Preparing the source failed for {}", name)));
+    }
+
+    Ok(DryRun {
+        port,
+        patches: list_patches(port_dir)?,
+    })
+}
+
+/// Build a package from its Portfile (shell-based, delegates to sh) and
+/// package the result into `out_dir`, returning the tarball paths: one, or
+/// one per split package
+pub fn build_from_portfile(
+    portfile: &Path,
+    cfg: &Config,
+    compression: Compression,
+    out_dir: &Path,
+) -> Result<Vec<PathBuf>> {
+    let port_dir = portfile
+        .parent()
+        .context("Invalid Portfile path")?;
+    let name = port_dir
+        .file_name()
+        .context("Invalid port directory")?
+        .to_string_lossy();
+    let port = read_portfile(portfile, cfg)?;
+
+    let work_dir = cfg.cache_dir.join("work").join(name.as_ref());
+    let pkg_dir = cfg.cache_dir.join("pkg").join(name.as_ref());
+
+    // Start from an empty $PKG so stale files don't end up in the package
+    if pkg_dir.exists() {
+        fs::remove_dir_all(&pkg_dir)?;
+    }
+    fs::create_dir_all(&work_dir)?;
+    fs::create_dir_all(&pkg_dir)?;
+
+    // Source the Portfile and run build()
+    let script = build_script(portfile, &port.source, port_dir, &work_dir, &pkg_dir, false);
+
+    let status = crate::deadline::status(Command::new("sh").arg("-c").arg(&script))?;
 
     if !status.success() {
-        bail!("Build failed for {}", name);
+        bail!(Error::Build(format!("Build failed for {}", name)));
     }
 
     eprintln!("  -> Build complete: {}", pkg_dir.display());
+
+    let category = port_dir
+        .parent()
+        .and_then(|d| d.file_name())
+        .map(|c| c.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let meta = PkgMeta {
+        name: port.name,
+        version: port.version,
+        arch: cfg.arch.clone(),
+        category,
+        description: port.description,
+        depends: port.depends,
+        files: BTreeMap::new(),
+    };
+
+    fs::create_dir_all(out_dir)
+        .with_context(|| format!("Failed to create {}", out_dir.display()))?;
+    let tarball_path = |meta: &PkgMeta| {
+        out_dir.join(format!(
+            "{}-{}-{}.{}",
+            meta.name,
+            meta.version,
+            meta.arch,
+            compression.extension()
+        ))
+    };
+
+    if port.packages.is_empty() {
+        let tarball = tarball_path(&meta);
+        create_package(&pkg_dir, meta, &tarball, compression, cfg.zstd_level)?;
+        return Ok(vec![tarball]);
+    }
+
+    let mut tarballs = Vec::new();
+    for sub in &port.packages {
+        let meta_path = pkg_dir.join(format!("{}.meta", sub));
+        let content = fs::read_to_string(&meta_path)
+            .with_context(|| format!("Failed to read {}", meta_path.display()))?;
+        let mut lines = content.lines();
+        let sub_meta = PkgMeta {
+            name: sub.clone(),
+            description: lines.next().unwrap_or_default().to_string(),
+            depends: lines
+                .next()
+                .unwrap_or_default()
+                .split_whitespace()
+                .map(String::from)
+                .collect(),
+            ..meta.clone()
+        };
+        let tarball = tarball_path(&sub_meta);
+        create_package(&pkg_dir.join(sub), sub_meta, &tarball, compression, cfg.zstd_level)?;
+        tarballs.push(tarball);
+    }
+    Ok(tarballs)
+}
+
+/// Package a staged `$PKG` directory into a tarball with an embedded
+/// `.PKGINFO`, plus a `.sha256` sidecar
+pub fn create_package(
+    pkg_dir: &Path,
+    mut meta: PkgMeta,
+    tarball: &Path,
+    compression: Compression,
+    zstd_level: i32,
+) -> Result<()> {
+    eprintln!("  -> Packaging {}...", tarball.display());
+
+    let mut files = Vec::new();
+    walk_dir(pkg_dir, &mut files)?;
+    for path in &files {
+        if fs::symlink_metadata(path)?.is_file() {
+            let rel = path.strip_prefix(pkg_dir)?.to_string_lossy().into_owned();
+            meta.files.insert(rel, crate::verify::sha256_file(path)?);
+        }
+    }
+    let pkginfo = serde_json::to_vec_pretty(&meta)?;
+
+    let out = fs::File::create(tarball)?;
+    match compression {
+        Compression::Zstd => {
+            let encoder = zstd::Encoder::new(out, zstd_level)?;
+            let mut file = write_tar(encoder, pkg_dir, &files, &pkginfo)?.finish()?;
+            file.flush()?;
+        }
+        Compression::Gzip => {
+            let encoder = flate2::write::GzEncoder::new(out, flate2::Compression::best());
+            let mut file = write_tar(encoder, pkg_dir, &files, &pkginfo)?.finish()?;
+            file.flush()?;
+        }
+        Compression::Xz => {
+            // Like extraction, xz goes through the system tool
+            let mut child = Command::new("xz")
+                .args(["-c", "-T0"])
+                .stdin(std::process::Stdio::piped())
+                .stdout(out)
+                .spawn()
+                .context("Failed to run xz")?;
+            let stdin = child.stdin.take().context("Failed to open xz stdin")?;
+            drop(write_tar(stdin, pkg_dir, &files, &pkginfo)?);
+            if !child.wait()?.success() {
+                bail!(Error::Build(format!("xz failed to compress {}", tarball.display())));
+            }
+        }
+    }
+
+    let sha256 = crate::verify::sha256_file(tarball)?;
+    let filename = tarball
+        .file_name()
+        .context("Invalid tarball path")?
+        .to_string_lossy();
+    fs::write(
+        format!("{}.sha256", tarball.display()),
+        format!("{}  {}\n", sha256, filename),
+    )?;
+
+    Ok(())
+}
+
+/// Write `.PKGINFO` followed by everything under `pkg_dir` as a tar stream
+fn write_tar<W: Write>(out: W, pkg_dir: &Path, files: &[PathBuf], pkginfo: &[u8]) -> Result<W> {
+    let mut builder = tar::Builder::new(out);
+    builder.follow_symlinks(false);
+
+    let mut header = tar::Header::new_gnu();
+    header.set_size(pkginfo.len() as u64);
+    header.set_mode(0o644);
+    header.set_cksum();
+    builder.append_data(&mut header, PKGINFO, pkginfo)?;
+
+    for path in files {
+        let rel = path.strip_prefix(pkg_dir)?;
+        builder.append_path_with_name(path, rel)?;
+    }
+
+    Ok(builder.into_inner()?)
+}
+
+/// Collect every path under `dir` (parents before children), not following symlinks
+fn walk_dir(dir: &Path, out: &mut Vec<PathBuf>) -> Result<()> {
+    let mut entries: Vec<_> = fs::read_dir(dir)?.collect::<std::io::Result<_>>()?;
+    entries.sort_by_key(|e| e.file_name());
+    for entry in entries {
+        let path = entry.path();
+        out.push(path.clone());
+        if entry.file_type()?.is_dir() {
+            walk_dir(&path, out)?;
+        }
+    }
     Ok(())
 }
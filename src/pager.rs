@@ -0,0 +1,50 @@
+use anyhow::Result;
+use std::io::{IsTerminal, Write};
+use std::process::{Command, Stdio};
+
+/// Print output, piping it through `$PAGER` (or `less`) when stdout is a
+/// terminal and the output doesn't fit on one screen
+pub fn page(output: &str, enabled: bool) -> Result<()> {
+    if !enabled || !std::io::stdout().is_terminal() || output.lines().count() < terminal_height() {
+        print!("{}", output);
+        return Ok(());
+    }
+
+    let pager = std::env::var("PAGER")
+        .ok()
+        .filter(|p| !p.trim().is_empty())
+        .unwrap_or_else(|| "less".to_string());
+
+    let mut cmd = Command::new("sh");
+    cmd.arg("-c").arg(&pager).stdin(Stdio::piped());
+    // Same defaults as git: raw colors, quit if one screen, no init
+    if std::env::var_os("LESS").is_none() {
+        cmd.env("LESS", "FRX");
+    }
+
+    let mut child = match cmd.spawn() {
+        Ok(child) => child,
+        Err(_) => {
+            print!("{}", output);
+            return Ok(());
+        }
+    };
+
+    if let Some(mut stdin) = child.stdin.take() {
+        // The user may quit the pager early; a broken pipe is fine
+        stdin.write_all(output.as_bytes()).ok();
+    }
+    child.wait()?;
+    Ok(())
+}
+
+fn terminal_height() -> usize {
+    // SAFETY: TIOCGWINSZ only fills in the winsize struct we pass
+    let mut ws: libc::winsize = unsafe { std::mem::zeroed() };
+    let ok = unsafe { libc::ioctl(libc::STDOUT_FILENO, libc::TIOCGWINSZ, &mut ws) } == 0;
+    if ok && ws.ws_row > 0 {
+        ws.ws_row as usize
+    } else {
+        24
+    }
+}
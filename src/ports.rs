@@ -0,0 +1,116 @@
+use crate::config::Config;
+use crate::error::Error;
+use anyhow::{bail, Context, Result};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+/// How a port changed between two revisions of the tree
+pub enum Change {
+    Added,
+    Updated,
+    Removed,
+}
+
+/// What syncing the ports tree did
+pub enum Synced {
+    /// A fresh clone, with this many ports
+    Cloned(usize),
+    /// A pull from one commit to another, with the ports it touched by
+    /// `category/name`
+    Pulled {
+        from: String,
+        to: String,
+        changes: BTreeMap<String, Change>,
+    },
+}
+
+/// Clone `ports_git` into `ports_dir`, or pull it if it's already there
+pub fn sync(cfg: &Config) -> Result<Synced> {
+    if cfg.ports_git.is_empty() {
+        bail!(Error::Config("No ports tree configured (set ports_git in the config)".to_string()));
+    }
+    let dir = &cfg.ports_dir;
+    // Running out of `--timeout` stays a timeout
+    let network = |e: anyhow::Error| {
+        if e.chain().any(|c| matches!(c.downcast_ref::<Error>(), Some(Error::Timeout(_)))) {
+            return e;
+        }
+        Error::Network(format!("{}: {:#}", cfg.ports_git, e)).into()
+    };
+
+    if !dir.join(".git").exists() {
+        if fs::read_dir(dir).is_ok_and(|mut entries| entries.next().is_some()) {
+            bail!(Error::Conflict(format!(
+                "{} exists but isn't a git checkout; move it aside to clone the ports tree",
+                dir.display()
+            )));
+        }
+        eprintln!("  -> Cloning {}...", cfg.ports_git);
+        run(Command::new("git")
+            .args(["clone", "--depth", "1", "--quiet", "--"])
+            .arg(&cfg.ports_git)
+            .arg(dir))
+        .map_err(network)?;
+        return Ok(Synced::Cloned(count_ports(dir)));
+    }
+
+    // Follow the configured URL even if it changed since the clone. `--`
+    // keeps a URL starting with `-` from being taken as an option.
+    run(git(dir).args(["remote", "set-url", "origin", "--", &cfg.ports_git]))?;
+    let from = run(git(dir).args(["rev-parse", "HEAD"]))?;
+    eprintln!("  -> Pulling {}...", cfg.ports_git);
+    run(git(dir).args(["pull", "--ff-only", "--quiet"])).map_err(network)?;
+    let to = run(git(dir).args(["rev-parse", "HEAD"]))?;
+
+    let mut changes = BTreeMap::new();
+    let diff = run(git(dir).args(["diff", "--name-status", "--no-renames", &from, &to]))?;
+    for line in diff.lines() {
+        let Some((status, path)) = line.split_once('\t') else {
+            continue;
+        };
+        let parts: Vec<_> = path.split('/').collect();
+        if parts.len() < 3 {
+            continue;
+        }
+        let port = format!("{}/{}", parts[0], parts[1]);
+        match (status, parts[2..].join("/").as_str()) {
+            ("A", "Portfile") => changes.insert(port, Change::Added),
+            ("D", "Portfile") => changes.insert(port, Change::Removed),
+            _ => {
+                changes.entry(port).or_insert(Change::Updated);
+                None
+            }
+        };
+    }
+    Ok(Synced::Pulled { from, to, changes })
+}
+
+fn git(dir: &Path) -> Command {
+    let mut cmd = Command::new("git");
+    cmd.arg("-C").arg(dir);
+    cmd
+}
+
+/// Run a git command under the deadline, returning its trimmed output
+fn run(cmd: &mut Command) -> Result<String> {
+    let output = crate::deadline::output(cmd).context("Failed to run git")?;
+    if !output.status.success() {
+        bail!("{}", String::from_utf8_lossy(&output.stderr).trim());
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+/// Ports in the tree: `<category>/<name>` directories with a Portfile
+fn count_ports(dir: &Path) -> usize {
+    fs::read_dir(dir)
+        .into_iter()
+        .flatten()
+        .flatten()
+        .filter_map(|category| fs::read_dir(category.path()).ok())
+        .flatten()
+        .flatten()
+        .filter(|port| port.path().join("Portfile").is_file())
+        .count()
+}
@@ -0,0 +1,95 @@
+use std::io::{IsTerminal, Write};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+/// Set once from `--quiet`
+static QUIET: AtomicBool = AtomicBool::new(false);
+
+pub fn set_quiet() {
+    QUIET.store(true, Ordering::Relaxed);
+}
+
+/// Whether to draw progress on stderr: only on a terminal, and not with `--quiet`
+pub fn enabled() -> bool {
+    !QUIET.load(Ordering::Relaxed) && std::io::stderr().is_terminal()
+}
+
+/// Operations shorter than this never show a spinner
+const DELAY: Duration = Duration::from_secs(1);
+const FRAMES: [char; 4] = ['|', '/', '-', '\\'];
+
+/// A spinner with elapsed time on stderr for as long as it's alive. It only
+/// appears once the operation has run for a second, and erases itself when
+/// dropped.
+pub struct Spinner {
+    stop: Arc<AtomicBool>,
+    message: Arc<Mutex<String>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Spinner {
+    pub fn start(message: &str) -> Self {
+        let stop = Arc::new(AtomicBool::new(false));
+        let message = Arc::new(Mutex::new(message.to_string()));
+        if !enabled() {
+            return Self {
+                stop,
+                message,
+                handle: None,
+            };
+        }
+
+        let shared = Arc::clone(&message);
+        let flag = Arc::clone(&stop);
+        let handle = std::thread::spawn(move || {
+            let start = Instant::now();
+            let mut shown = false;
+            let mut frame = 0;
+            loop {
+                std::thread::park_timeout(Duration::from_millis(100));
+                if flag.load(Ordering::Relaxed) {
+                    break;
+                }
+                let elapsed = start.elapsed();
+                if elapsed < DELAY {
+                    continue;
+                }
+                eprint!(
+                    "\r\x1b[K  -> {} {} {}s",
+                    shared.lock().unwrap(),
+                    FRAMES[frame % FRAMES.len()],
+                    elapsed.as_secs()
+                );
+                std::io::stderr().flush().ok();
+                shown = true;
+                frame += 1;
+            }
+            if shown {
+                eprint!("\r\x1b[K");
+            }
+        });
+        Self {
+            stop,
+            message,
+            handle: Some(handle),
+        }
+    }
+
+    /// Show `message` from the next frame on, e.g. to report progress
+    pub fn set_message(&self, message: String) {
+        *self.message.lock().unwrap() = message;
+    }
+}
+
+impl Drop for Spinner {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(handle) = self.handle.take() {
+            // Wake the thread so finishing never waits out a frame
+            handle.thread().unpark();
+            handle.join().ok();
+        }
+    }
+}
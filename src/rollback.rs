@@ -0,0 +1,108 @@
+use crate::config::Config;
+use crate::db::{Database, InstalledPackage};
+use crate::fetch;
+use anyhow::{Context, Result};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// A tarball kept for rolling back an upgrade
+pub struct Kept {
+    pub name: String,
+    pub version: String,
+    pub path: PathBuf,
+    pub size: u64,
+}
+
+/// Where tarballs of replaced versions are kept, apart from the download
+/// cache so `clean` leaves them alone: each under `<name>/<version>/`, with
+/// its filename as the repo had it
+pub fn dir(cfg: &Config) -> PathBuf {
+    cfg.cache_dir.join("rollback")
+}
+
+/// Every kept tarball, sorted by name and then newest version first
+pub fn kept(cfg: &Config) -> Result<Vec<Kept>> {
+    let dir = dir(cfg);
+    if !dir.is_dir() {
+        return Ok(Vec::new());
+    }
+    let mut found = Vec::new();
+    for (name, name_dir) in subdirs(&dir)? {
+        for (version, version_dir) in subdirs(&name_dir)? {
+            for entry in fs::read_dir(&version_dir)? {
+                let entry = entry?;
+                let meta = entry.metadata()?;
+                if meta.is_file() {
+                    found.push(Kept {
+                        name: name.clone(),
+                        version: version.clone(),
+                        path: entry.path(),
+                        size: meta.len(),
+                    });
+                }
+            }
+        }
+    }
+    found.sort_by(|a, b| {
+        a.name
+            .cmp(&b.name)
+            .then_with(|| crate::version::compare(&b.version, &a.version))
+    });
+    Ok(found)
+}
+
+/// The directories directly in `dir`, with their names
+fn subdirs(dir: &Path) -> Result<Vec<(String, PathBuf)>> {
+    let mut found = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            found.push((entry.file_name().to_string_lossy().into_owned(), entry.path()));
+        }
+    }
+    Ok(found)
+}
+
+/// Keep the cached tarball of `old`, which an upgrade is about to replace,
+/// then drop all but the newest `rollback_keep` versions of the package.
+/// Does nothing when `rollback_keep` is 0.
+pub fn retain(cfg: &Config, old: &InstalledPackage) -> Result<()> {
+    if cfg.rollback_keep == 0 {
+        return Ok(());
+    }
+    let db = Database::load(cfg)?;
+    let cached = db
+        .find_version(&old.name, &old.version)
+        .map(|pkg| fetch::cache_path(cfg, pkg))
+        .filter(|path| path.is_file());
+    let Some(cached) = cached else {
+        eprintln!(
+            "  -> No cached tarball of {} {} to keep for rollback",
+            old.name, old.version
+        );
+        return Ok(());
+    };
+
+    let dir = dir(cfg).join(&old.name).join(&old.version);
+    fs::create_dir_all(&dir)?;
+    let dest = dir.join(cached.file_name().unwrap_or_default());
+    if !dest.exists() {
+        fs::hard_link(&cached, &dest)
+            .or_else(|_| fs::copy(&cached, &dest).map(|_| ()))
+            .with_context(|| format!("Failed to keep {} for rollback", cached.display()))?;
+        eprintln!("  -> Kept {} {} for rollback", old.name, old.version);
+    }
+
+    for stale in kept(cfg)?
+        .into_iter()
+        .filter(|k| k.name == old.name)
+        .skip(cfg.rollback_keep)
+    {
+        fs::remove_file(&stale.path)?;
+        // Only succeeds once the version has no tarball left
+        if let Some(version_dir) = stale.path.parent() {
+            fs::remove_dir(version_dir).ok();
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,115 @@
+use crate::config::Config;
+use crate::configs;
+use anyhow::Result;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::ErrorKind;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+
+/// Content-addressed store of installed files, shared through hardlinks
+fn store_dir(cfg: &Config) -> PathBuf {
+    cfg.cache_dir.join("store")
+}
+
+/// Replace freshly extracted files whose content is already in the store
+/// with links to it, and add the rest to the store for later packages. Links
+/// share mode and ownership, so a file is only linked to an entry that has
+/// the same mode, owner and group. Config files are left alone, so editing
+/// one can't change another package's copy. Returns the number of files
+/// linked and the bytes saved. Files on another filesystem than the store
+/// are left as written.
+pub fn dedup_files(
+    cfg: &Config,
+    root: &Path,
+    hashes: &BTreeMap<String, String>,
+) -> Result<(usize, u64)> {
+    let mut linked = 0;
+    let mut saved = 0;
+
+    for (file, hash) in hashes {
+        if configs::is_config(file) {
+            continue;
+        }
+        let path = root.join(file);
+        let meta = fs::symlink_metadata(&path)?;
+        let mode = meta.permissions().mode() & 0o7777;
+        let entry = store_dir(cfg)
+            .join(&hash[..2])
+            .join(format!("{}-{:o}", hash, mode));
+
+        match fs::metadata(&entry) {
+            Ok(stored) if stored.ino() == meta.ino() && stored.dev() == meta.dev() => {}
+            // Changed through another link since it was stored
+            Ok(stored)
+                if stored.mode() & 0o7777 != mode
+                    || stored.uid() != meta.uid()
+                    || stored.gid() != meta.gid() => {}
+            Ok(_) => {
+                if link_over(&entry, &path)? {
+                    linked += 1;
+                    saved += meta.len();
+                }
+            }
+            Err(_) => {
+                if let Some(parent) = entry.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+                match fs::hard_link(&path, &entry) {
+                    Ok(()) => {}
+                    Err(e) if e.kind() == ErrorKind::CrossesDevices => {}
+                    Err(e) => return Err(e.into()),
+                }
+            }
+        }
+    }
+
+    Ok((linked, saved))
+}
+
+/// Drop store entries no installed file links to any more (a link count of
+/// 1), and directories left empty. Returns the number of entries removed and
+/// the bytes freed.
+pub fn prune(cfg: &Config) -> Result<(usize, u64)> {
+    let dir = store_dir(cfg);
+    if !dir.is_dir() {
+        return Ok((0, 0));
+    }
+    let mut removed = 0;
+    let mut freed = 0;
+    for prefix in fs::read_dir(&dir)? {
+        let prefix = prefix?.path();
+        if !prefix.is_dir() {
+            continue;
+        }
+        for entry in fs::read_dir(&prefix)? {
+            let entry = entry?;
+            let meta = entry.metadata()?;
+            if meta.is_file() && meta.nlink() == 1 {
+                fs::remove_file(entry.path())?;
+                removed += 1;
+                freed += meta.len();
+            }
+        }
+        // Only succeeds once nothing is left in it
+        fs::remove_dir(&prefix).ok();
+    }
+    Ok((removed, freed))
+}
+
+/// Atomically replace `path` with a hardlink to `entry`; false if they live
+/// on different filesystems
+fn link_over(entry: &Path, path: &Path) -> Result<bool> {
+    let tmp = path.with_file_name(format!(
+        ".{}.scarab-link",
+        path.file_name().unwrap_or_default().to_string_lossy()
+    ));
+    fs::remove_file(&tmp).ok();
+    match fs::hard_link(entry, &tmp) {
+        Ok(()) => {}
+        Err(e) if e.kind() == ErrorKind::CrossesDevices => return Ok(false),
+        Err(e) => return Err(e.into()),
+    }
+    fs::rename(&tmp, path)?;
+    Ok(true)
+}
@@ -0,0 +1,45 @@
+use colored::Colorize;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Instant;
+
+/// Set once from `--time`; phases are only measured and reported when on
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+pub fn enable() {
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+/// The first phase that failed, on any thread (for the history log). Never
+/// cleared, so a phase finishing later elsewhere can't hide the failure.
+static FAILED: Mutex<Option<&'static str>> = Mutex::new(None);
+
+fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Run `f` and, with `--time`, report how long the `name` phase took
+pub fn phase<T>(name: &'static str, f: impl FnOnce() -> anyhow::Result<T>) -> anyhow::Result<T> {
+    let start = Instant::now();
+    let result = f();
+    if result.is_err() {
+        FAILED.lock().unwrap().get_or_insert(name);
+    }
+    if enabled() {
+        eprintln!("  {} {:<9} {:.2?}", "time:".dimmed(), name, start.elapsed());
+    }
+    result
+}
+
+/// The phase a failed command stopped in, if it failed inside one: the first
+/// to fail
+pub fn failed_phase() -> Option<&'static str> {
+    *FAILED.lock().unwrap()
+}
+
+/// With `--time`, report the wall time of the whole command
+pub fn total(start: Instant) {
+    if enabled() {
+        eprintln!("  {} {:<9} {:.2?}", "time:".dimmed(), "total", start.elapsed());
+    }
+}
@@ -1,10 +1,44 @@
+use crate::config::Config;
+use crate::error::Error;
+use crate::fetch;
+use crate::keyring::{self, PublicKey};
 use crate::package::PackageInfo;
 use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap};
+use std::ffi::OsString;
 use std::fs;
-use std::path::Path;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Component, Path, PathBuf};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+/// An installed file and the hash recorded for it
+pub struct FileCheck {
+    pub package: String,
+    /// Root the package was installed into
+    pub root: PathBuf,
+    pub path: String,
+    pub expected: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileStatus {
+    Missing,
+    Modified,
+}
 
 pub fn verify_package(path: &Path, pkg: &PackageInfo) -> Result<()> {
+    if pkg.sha256.is_empty() && pkg.url.is_some() {
+        // The checksum is all that ties an externally hosted tarball to the repo
+        bail!(Error::Verification(format!(
+            "{} is downloaded from {} but has no checksum",
+            pkg.name,
+            pkg.url.as_deref().unwrap_or_default()
+        )));
+    }
     if pkg.sha256.is_empty() {
         eprintln!("  -> Warning: no checksum for {}, skipping verification", pkg.name);
         return Ok(());
@@ -12,25 +46,58 @@ pub fn verify_package(path: &Path, pkg: &PackageInfo) -> Result<()> {
 
     eprintln!("  -> Verifying checksum...");
 
-    let data = fs::read(path).with_context(|| format!("Failed to read {}", path.display()))?;
-
-    let mut hasher = Sha256::new();
-    hasher.update(&data);
-    let result = hex::encode(hasher.finalize());
+    let result = sha256_file(path).with_context(|| format!("Failed to read {}", path.display()))?;
 
     if result != pkg.sha256 {
-        bail!(
+        bail!(Error::Verification(format!(
             "Checksum mismatch for {}!\n  Expected: {}\n  Got:      {}",
-            pkg.name,
-            pkg.sha256,
-            result
-        );
+            pkg.name, pkg.sha256, result
+        )));
     }
 
     eprintln!("  -> Checksum OK");
     Ok(())
 }
 
+/// Check a package's signature when the repo lists one, trusting the
+/// `signing_key` if set and the keyring otherwise. Unsigned packages pass
+/// with a warning.
+pub fn verify_package_signature(cfg: &Config, path: &Path, pkg: &PackageInfo) -> Result<()> {
+    let Some(sig_path) = fetch::sig_path(cfg, pkg) else {
+        eprintln!("  -> Warning: {} is not signed, relying on its checksum", pkg.name);
+        return Ok(());
+    };
+    let keys = match &cfg.signing_key {
+        Some(key) => {
+            let content = fs::read_to_string(key)
+                .with_context(|| format!("Failed to read signing key {}", key.display()))?;
+            vec![keyring::parse_public_key(&content)
+                .with_context(|| format!("Invalid signing key {}", key.display()))?]
+        }
+        None => keyring::list_keys(cfg)?,
+    };
+    if keys.is_empty() {
+        bail!(Error::Verification(format!(
+            "{} is signed but no key is trusted (set signing_key or use `scarab key add`)",
+            pkg.name
+        )));
+    }
+
+    eprintln!("  -> Verifying signature...");
+    verify_signature(path, &sig_path, &keys)
+        .map_err(|e| Error::Verification(format!("Bad signature for {}: {:#}", pkg.name, e)))?;
+    eprintln!("  -> Signature OK");
+    Ok(())
+}
+
+/// Check the detached minisign signature at `sig_path` for the file at `path`
+pub fn verify_signature(path: &Path, sig_path: &Path, keys: &[PublicKey]) -> Result<()> {
+    let data = fs::read(path).with_context(|| format!("Failed to read {}", path.display()))?;
+    let signature = fs::read_to_string(sig_path)
+        .with_context(|| format!("Failed to read {}", sig_path.display()))?;
+    keyring::verify_with(keys, &data, &signature)
+}
+
 /// Compute SHA256 of a file
 pub fn sha256_file(path: &Path) -> Result<String> {
     let data = fs::read(path)?;
@@ -38,3 +105,193 @@ pub fn sha256_file(path: &Path) -> Result<String> {
     hasher.update(&data);
     Ok(hex::encode(hasher.finalize()))
 }
+
+/// Hash the regular files among `files` (relative to `root`)
+pub fn hash_files(root: &Path, files: &[String]) -> Result<BTreeMap<String, String>> {
+    let mut hashes = BTreeMap::new();
+    for file in files {
+        let path = root.join(file);
+        if fs::symlink_metadata(&path).is_ok_and(|m| m.is_file()) {
+            hashes.insert(file.clone(), sha256_file(&path)?);
+        }
+    }
+    Ok(hashes)
+}
+
+/// Whether the symlink at `link` (a path under `root`) leads to something
+/// that exists, resolved as if `root` were `/`: absolute targets, whether of
+/// the link itself or of a symlinked directory on the way, stay inside `root`
+pub fn link_resolves(root: &Path, link: &Path) -> bool {
+    let Ok(rel) = link.strip_prefix(root) else {
+        return false;
+    };
+    // Components still to walk, the next one last, and those walked so far
+    let mut pending: Vec<OsString> = rel.iter().rev().map(OsString::from).collect();
+    let mut walked = PathBuf::new();
+    let mut hops = 0;
+    while let Some(part) = pending.pop() {
+        if part == ".." {
+            // `..` never climbs out of `root`, as at the real `/`
+            walked.pop();
+            continue;
+        }
+        walked.push(&part);
+        let path = root.join(&walked);
+        match fs::read_link(&path) {
+            Ok(target) => {
+                // As many hops as the kernel allows before giving up with ELOOP
+                hops += 1;
+                if hops > 40 {
+                    return false;
+                }
+                walked.pop();
+                if target.has_root() {
+                    walked.clear();
+                }
+                pending.extend(
+                    target
+                        .components()
+                        .rev()
+                        .filter(|c| matches!(c, Component::Normal(_) | Component::ParentDir))
+                        .map(|c| c.as_os_str().to_owned()),
+                );
+            }
+            Err(_) if fs::symlink_metadata(&path).is_err() => return false,
+            Err(_) => {}
+        }
+    }
+    true
+}
+
+/// Hashes from earlier `verify` runs, reused for files whose size, mtime,
+/// ctime and inode haven't changed since
+#[derive(Default, Serialize, Deserialize)]
+pub struct HashCache {
+    files: HashMap<PathBuf, CachedHash>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct CachedHash {
+    size: u64,
+    mtime: i64,
+    mtime_nsec: i64,
+    /// Also kept, since mtime can be set back to hide a change but ctime can't
+    ctime: i64,
+    ctime_nsec: i64,
+    ino: u64,
+    sha256: String,
+}
+
+impl HashCache {
+    fn path(cfg: &Config) -> PathBuf {
+        cfg.db_dir.join("verify_cache.json")
+    }
+
+    /// The saved cache, or an empty one if there is none or it can't be read
+    pub fn load(cfg: &Config) -> Self {
+        fs::read(Self::path(cfg))
+            .ok()
+            .and_then(|content| serde_json::from_slice(&content).ok())
+            .unwrap_or_default()
+    }
+
+    /// Drop the cached hash of `path`, so it's read again
+    pub fn forget(&mut self, path: &Path) {
+        self.files.remove(path);
+    }
+
+    /// Write the cache back, dropping files that no longer exist
+    pub fn save(mut self, cfg: &Config) -> Result<()> {
+        self.files.retain(|path, _| fs::symlink_metadata(path).is_ok());
+        let path = Self::path(cfg);
+        let tmp = path.with_extension("json.part");
+        fs::write(&tmp, serde_json::to_vec(&self)?)
+            .and_then(|_| fs::rename(&tmp, &path))
+            .inspect_err(|_| {
+                fs::remove_file(&tmp).ok();
+            })
+            .with_context(|| format!("Failed to write {}", path.display()))
+    }
+}
+
+/// SHA256 of `path`, taken from `cache` when its size, mtime, ctime and
+/// inode match the cached entry, and stored there otherwise
+fn sha256_cached(path: &Path, cache: Option<&Mutex<HashCache>>) -> Result<String> {
+    let Some(cache) = cache else {
+        return sha256_file(path);
+    };
+    let meta = fs::metadata(path)?;
+    let unchanged = |entry: &CachedHash| {
+        entry.size == meta.len()
+            && entry.mtime == meta.mtime()
+            && entry.mtime_nsec == meta.mtime_nsec()
+            && entry.ctime == meta.ctime()
+            && entry.ctime_nsec == meta.ctime_nsec()
+            && entry.ino == meta.ino()
+    };
+    if let Some(entry) = cache.lock().unwrap().files.get(path)
+        && unchanged(entry)
+    {
+        return Ok(entry.sha256.clone());
+    }
+
+    let sha256 = sha256_file(path)?;
+    let entry = CachedHash {
+        size: meta.len(),
+        mtime: meta.mtime(),
+        mtime_nsec: meta.mtime_nsec(),
+        ctime: meta.ctime(),
+        ctime_nsec: meta.ctime_nsec(),
+        ino: meta.ino(),
+        sha256: sha256.clone(),
+    };
+    cache.lock().unwrap().files.insert(path.to_path_buf(), entry);
+    Ok(sha256)
+}
+
+/// Re-hash installed files on up to `jobs` threads, returning the ones that
+/// are missing or no longer match. With a `cache`, files whose size, mtime,
+/// ctime and inode are unchanged since it last hashed them aren't read again.
+/// Progress goes to stderr on a terminal (unless `--quiet`).
+pub fn check_files<'a>(
+    files: &'a [FileCheck],
+    jobs: usize,
+    cache: Option<&Mutex<HashCache>>,
+) -> Vec<(&'a FileCheck, FileStatus)> {
+    let next = AtomicUsize::new(0);
+    let done = AtomicUsize::new(0);
+    let problems = Mutex::new(Vec::new());
+    let progress = crate::progress::enabled();
+
+    std::thread::scope(|scope| {
+        for _ in 0..jobs.clamp(1, files.len().max(1)) {
+            scope.spawn(|| {
+                loop {
+                    let i = next.fetch_add(1, Ordering::Relaxed);
+                    let Some(check) = files.get(i) else { break };
+                    let status = match sha256_cached(&check.root.join(&check.path), cache) {
+                        Ok(hash) if hash == check.expected => None,
+                        Ok(_) => Some(FileStatus::Modified),
+                        Err(_) => Some(FileStatus::Missing),
+                    };
+                    if let Some(status) = status {
+                        problems.lock().unwrap().push((check, status));
+                    }
+                    done.fetch_add(1, Ordering::Relaxed);
+                }
+            });
+        }
+
+        while progress && done.load(Ordering::Relaxed) < files.len() {
+            eprint!("\r  -> Checked {}/{} files", done.load(Ordering::Relaxed), files.len());
+            std::thread::sleep(Duration::from_millis(100));
+        }
+    });
+    if progress {
+        eprintln!("\r  -> Checked {}/{} files", files.len(), files.len());
+    }
+
+    let mut problems = problems.into_inner().unwrap();
+    problems.sort_by(|a, b| (&a.0.package, &a.0.path).cmp(&(&b.0.package, &b.0.path)));
+    problems
+}
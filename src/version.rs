@@ -0,0 +1,119 @@
+use std::cmp::Ordering;
+
+/// Compare two versions of the form `[epoch:]version`. A higher epoch always
+/// wins (a missing one is 0); otherwise see [`compare_plain`].
+pub fn compare(a: &str, b: &str) -> Ordering {
+    let (epoch_a, a) = split_epoch(a);
+    let (epoch_b, b) = split_epoch(b);
+    epoch_a.cmp(&epoch_b).then_with(|| compare_plain(a, b))
+}
+
+/// Whether `version` is something `compare` can order meaningfully: a
+/// numeric epoch if any, and a non-empty version with no whitespace
+pub fn is_valid(version: &str) -> bool {
+    let rest = match version.split_once(':') {
+        Some((epoch, rest)) => {
+            if epoch.is_empty() || !epoch.bytes().all(|b| b.is_ascii_digit()) {
+                return false;
+            }
+            rest
+        }
+        None => version,
+    };
+    rest.chars().any(|c| c.is_ascii_alphanumeric()) && !rest.chars().any(char::is_whitespace)
+}
+
+fn split_epoch(version: &str) -> (u64, &str) {
+    match version.split_once(':') {
+        Some((epoch, rest)) if !epoch.is_empty() && epoch.bytes().all(|b| b.is_ascii_digit()) => {
+            (epoch.parse().unwrap_or(u64::MAX), rest)
+        }
+        _ => (0, version),
+    }
+}
+
+/// Compare two version strings segment by segment: runs of digits compare
+/// numerically, runs of letters lexically, and separators (`.`, `-`, `_`,
+/// `+`, ...) only split segments. Numbers sort after letters. An extra
+/// trailing number makes a version newer (`1.10` > `1.9`, `1.0.1` > `1.0`),
+/// but extra trailing letters mark a pre-release, which is older
+/// (`1.0rc1` < `1.0`), as with pacman and rpm.
+fn compare_plain(a: &str, b: &str) -> Ordering {
+    let (mut a, mut b) = (segments(a), segments(b));
+    loop {
+        match (a.next(), b.next()) {
+            (None, None) => return Ordering::Equal,
+            (Some(x), None) => return trailing(x),
+            (None, Some(y)) => return trailing(y).reverse(),
+            (Some(x), Some(y)) => {
+                let ord = match (is_numeric(x), is_numeric(y)) {
+                    (true, true) => {
+                        let (x, y) = (x.trim_start_matches('0'), y.trim_start_matches('0'));
+                        x.len().cmp(&y.len()).then_with(|| x.cmp(y))
+                    }
+                    (true, false) => Ordering::Greater,
+                    (false, true) => Ordering::Less,
+                    (false, false) => x.cmp(y),
+                };
+                if ord != Ordering::Equal {
+                    return ord;
+                }
+            }
+        }
+    }
+}
+
+/// How a version with `extra` left over compares to one that has ended
+fn trailing(extra: &str) -> Ordering {
+    if is_numeric(extra) {
+        Ordering::Greater
+    } else {
+        Ordering::Less
+    }
+}
+
+fn is_numeric(segment: &str) -> bool {
+    segment.starts_with(|c: char| c.is_ascii_digit())
+}
+
+/// Split into maximal runs of ASCII digits or letters, dropping everything else
+fn segments(version: &str) -> impl Iterator<Item = &str> {
+    let mut rest = version;
+    std::iter::from_fn(move || {
+        rest = rest.trim_start_matches(|c: char| !c.is_ascii_alphanumeric());
+        let first = rest.chars().next()?;
+        let end = rest
+            .find(|c: char| {
+                !c.is_ascii_alphanumeric() || c.is_ascii_digit() != first.is_ascii_digit()
+            })
+            .unwrap_or(rest.len());
+        let (segment, tail) = rest.split_at(end);
+        rest = tail;
+        Some(segment)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::compare;
+    use std::cmp::Ordering;
+
+    #[test]
+    fn prerelease_is_older_than_release() {
+        assert_eq!(compare("1.0rc1", "1.0"), Ordering::Less);
+        assert_eq!(compare("1.0", "1.0beta"), Ordering::Greater);
+        assert_eq!(compare("1.0rc1", "1.0rc2"), Ordering::Less);
+    }
+
+    #[test]
+    fn trailing_number_is_newer() {
+        assert_eq!(compare("1.0.1", "1.0"), Ordering::Greater);
+        assert_eq!(compare("1.10", "1.9"), Ordering::Greater);
+    }
+
+    #[test]
+    fn epoch_wins() {
+        assert_eq!(compare("1:0.5", "2.0"), Ordering::Greater);
+        assert_eq!(compare("2.0", "1:0.5"), Ordering::Less);
+    }
+}